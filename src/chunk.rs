@@ -1,9 +1,12 @@
 #![allow(non_camel_case_types)]
 use num_enum::{IntoPrimitive, TryFromPrimitive};
 
-use crate::value::Value;
+use crate::{
+    instruction::{self, Instruction, Instructions},
+    value::Value,
+};
 
-#[derive(IntoPrimitive, TryFromPrimitive)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, IntoPrimitive, TryFromPrimitive)]
 #[repr(u8)]
 pub enum OpCode {
     OP_RETURN,
@@ -13,25 +16,95 @@ pub enum OpCode {
     OP_MULTIPLY,
     OP_DIVIDE,
     OP_CONSTANT,
+    OP_NIL,
+    OP_TRUE,
+    OP_FALSE,
+    OP_NOT,
+    OP_EQUAL,
+    OP_GREATER,
+    OP_LESS,
+    OP_PRINT,
+    OP_POP,
+    OP_DEFINE_GLOBAL,
+    OP_GET_GLOBAL,
+    OP_SET_GLOBAL,
+    OP_GET_LOCAL,
+    OP_SET_LOCAL,
+    OP_JUMP,
+    OP_JUMP_IF_FALSE,
+    OP_LOOP,
+    OP_CALL,
+    OP_CLOSURE,
+    OP_GET_UPVALUE,
+    OP_SET_UPVALUE,
+    OP_CLOSE_UPVALUE,
+    OP_CLASS,
+    OP_GET_PROPERTY,
+    OP_SET_PROPERTY,
+    OP_METHOD,
+    OP_INVOKE,
+    OP_INHERIT,
+    OP_GET_SUPER,
+    OP_SUPER_INVOKE,
+    // Forms of the instructions above that index the constant table with a
+    // 24-bit operand instead of a single byte.
+    OP_CONSTANT_LONG,
+    OP_DEFINE_GLOBAL_LONG,
+    OP_GET_GLOBAL_LONG,
+    OP_SET_GLOBAL_LONG,
+    OP_CLOSURE_LONG,
+    OP_CLASS_LONG,
+    OP_GET_PROPERTY_LONG,
+    OP_SET_PROPERTY_LONG,
+    OP_METHOD_LONG,
+    OP_INVOKE_LONG,
+    OP_GET_SUPER_LONG,
+    OP_SUPER_INVOKE_LONG,
 }
 
-#[derive(Default)]
+/// The number of constants a chunk can hold, limited by the 24-bit operand of
+/// the long instructions.
+pub const MAX_CONSTANTS: usize = 1 << 24;
+
+impl OpCode {
+    /// The long form of an instruction that indexes the constant table.
+    pub fn long_form(self) -> Option<OpCode> {
+        match self {
+            OpCode::OP_CONSTANT => Some(OpCode::OP_CONSTANT_LONG),
+            OpCode::OP_DEFINE_GLOBAL => Some(OpCode::OP_DEFINE_GLOBAL_LONG),
+            OpCode::OP_GET_GLOBAL => Some(OpCode::OP_GET_GLOBAL_LONG),
+            OpCode::OP_SET_GLOBAL => Some(OpCode::OP_SET_GLOBAL_LONG),
+            OpCode::OP_CLOSURE => Some(OpCode::OP_CLOSURE_LONG),
+            OpCode::OP_CLASS => Some(OpCode::OP_CLASS_LONG),
+            OpCode::OP_GET_PROPERTY => Some(OpCode::OP_GET_PROPERTY_LONG),
+            OpCode::OP_SET_PROPERTY => Some(OpCode::OP_SET_PROPERTY_LONG),
+            OpCode::OP_METHOD => Some(OpCode::OP_METHOD_LONG),
+            OpCode::OP_INVOKE => Some(OpCode::OP_INVOKE_LONG),
+            OpCode::OP_GET_SUPER => Some(OpCode::OP_GET_SUPER_LONG),
+            OpCode::OP_SUPER_INVOKE => Some(OpCode::OP_SUPER_INVOKE_LONG),
+            _ => None,
+        }
+    }
+}
+
+/// A run of bytecode that all came from the same source line.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct LineStart {
+    /// The offset of the first byte in the run.
+    offset: usize,
+    line: usize,
+}
+
+#[derive(Debug, Default)]
 pub struct Chunk {
     pub codes: Vec<u8>,
     pub values: Vec<Value>,
-    pub lines: Vec<usize>,
+    /// Run-length encoded source lines: an entry for each place where the
+    /// line changes, rather than one per byte.
+    lines: Vec<LineStart>,
 }
 
 impl Chunk {
-    pub fn make_constant(&mut self, value: Value) -> u8 {
-        let c = self.add_constant(value);
-        if c > (u8::MAX as usize) {
-            panic!("Too many constants in chunk!");
-        } else {
-            c as u8
-        }
-    }
-
     pub fn add_constant(&mut self, value: Value) -> usize {
         let index = self.values.len();
         self.values.push(value);
@@ -39,7 +112,63 @@ impl Chunk {
     }
 
     pub fn write_chunk(&mut self, byte: u8, line: usize) {
+        if self.lines.last().is_none_or(|start| start.line != line) {
+            self.lines.push(LineStart {
+                offset: self.codes.len(),
+                line,
+            });
+        }
         self.codes.push(byte);
-        self.lines.push(line);
+    }
+
+    /// The source line of the byte at `offset`.
+    pub fn line_at(&self, offset: usize) -> usize {
+        let run = self.lines.partition_point(|start| start.offset <= offset);
+        self.lines[run - 1].line
+    }
+
+    /// The memory reserved for the line table, in bytes.
+    pub fn line_table_size(&self) -> usize {
+        self.lines.capacity() * size_of::<LineStart>()
+    }
+
+    /// Decodes the instruction at `offset`, returning it with the offset of
+    /// the next one.
+    #[inline]
+    pub fn instruction_at(&self, offset: usize) -> (Instruction, usize) {
+        instruction::decode(self, offset)
+    }
+
+    /// The chunk's instructions in order, with their offsets and source
+    /// lines.
+    pub fn instructions(&self) -> Instructions<'_> {
+        Instructions::new(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{compile::compile, vm::VM};
+
+    #[test]
+    fn line_table_is_smaller_than_one_line_per_byte() {
+        let source: String = (0..10_000)
+            .map(|i| format!("var v{i} = {i} * 2 + 1;\nprint v{i} - 3;\n"))
+            .collect();
+        let mut vm = VM::new();
+        let function = compile(&source, &mut vm).unwrap();
+        let chunk = &vm.heap.as_function(function).chunk;
+
+        assert_eq!(chunk.line_at(0), 1);
+        // The implicit return is compiled at the end of the input, after the
+        // last newline.
+        assert_eq!(chunk.line_at(chunk.codes.len() - 1), 20_001);
+        let per_byte_size = chunk.codes.len() * size_of::<usize>();
+        assert!(
+            chunk.line_table_size() * 2 < per_byte_size,
+            "{} bytes of line table, against {} for one line per byte",
+            chunk.line_table_size(),
+            per_byte_size
+        );
     }
 }
@@ -1,125 +1,337 @@
+#![allow(clippy::upper_case_acronyms)]
 use num_enum::{IntoPrimitive, TryFromPrimitive};
 
 use crate::{
-    chunk::{Chunk, OpCode},
-    debug::disassemble_chunk,
+    chunk::{Chunk, OpCode, MAX_CONSTANTS},
+    diagnostic::{Diagnostic, Location, Severity},
+    object::{ObjFunction, ObjRef, Object},
     scanner::{Scanner, Token, TokenType},
     value::Value,
+    vm::VM,
 };
 
+const UINT8_COUNT: usize = u8::MAX as usize + 1;
+
+#[derive(Debug)]
+struct Local<'a> {
+    name: &'a str,
+    /// `None` while the variable's initializer is being compiled.
+    depth: Option<usize>,
+    /// Set when a closure captures the variable, so it is moved to the heap
+    /// instead of popped when it goes out of scope.
+    is_captured: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Upvalue {
+    /// A local slot in the enclosing function if `is_local`, otherwise an
+    /// index into the enclosing function's own upvalues.
+    index: u8,
+    is_local: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum FunctionType {
+    Function,
+    Initializer,
+    Method,
+    Script,
+}
+
+/// The state for a class declaration enclosing the code being compiled.
+#[derive(Debug)]
+struct ClassCompiler {
+    has_superclass: bool,
+}
+
+/// The state for the function currently being compiled. Nested function
+/// declarations push a new compiler onto the parser's stack.
 #[derive(Debug)]
+struct Compiler<'a> {
+    function: ObjFunction,
+    function_type: FunctionType,
+    locals: Vec<Local<'a>>,
+    upvalues: Vec<Upvalue>,
+    scope_depth: usize,
+}
+
+impl<'a> Compiler<'a> {
+    fn new(function_type: FunctionType, name: Option<ObjRef>) -> Self {
+        let mut locals = Vec::with_capacity(UINT8_COUNT);
+        // The first slot holds the function being called, or the receiver
+        // for methods, where it can be read as `this`.
+        locals.push(Local {
+            name: match function_type {
+                FunctionType::Method | FunctionType::Initializer => "this",
+                FunctionType::Function | FunctionType::Script => "",
+            },
+            depth: Some(0),
+            is_captured: false,
+        });
+        Compiler {
+            function: ObjFunction {
+                arity: 0,
+                chunk: Chunk::default(),
+                name,
+            },
+            function_type,
+            locals,
+            upvalues: Vec::new(),
+            scope_depth: 0,
+        }
+    }
+}
+
 struct Parser<'a> {
     current: Option<Token>,
     previous: Option<Token>,
-    string: &'a String,
-    had_error: bool,
+    string: &'a str,
+    vm: &'a mut VM,
+    diagnostics: Vec<Diagnostic>,
     panic_mode: bool,
+    compilers: Vec<Compiler<'a>>,
+    /// The class declarations enclosing the code being compiled, innermost
+    /// last.
+    classes: Vec<ClassCompiler>,
+    /// Print a trailing top-level expression instead of requiring a
+    /// semicolon after it.
+    repl: bool,
 }
 
 impl<'a> Parser<'a> {
-    pub fn new(source: &'a String) -> Self {
+    pub fn new(source: &'a str, vm: &'a mut VM) -> Self {
         Parser {
             current: None,
             previous: None,
             string: source,
-            had_error: false,
+            vm,
+            diagnostics: Vec::new(),
             panic_mode: false,
+            compilers: vec![Compiler::new(FunctionType::Script, None)],
+            classes: Vec::new(),
+            repl: false,
         }
     }
 
+    fn compiler(&self) -> &Compiler<'a> {
+        self.compilers.last().unwrap()
+    }
+
+    fn compiler_mut(&mut self) -> &mut Compiler<'a> {
+        self.compilers.last_mut().unwrap()
+    }
+
+    fn current_chunk(&mut self) -> &mut Chunk {
+        &mut self.compiler_mut().function.chunk
+    }
+
     fn advance(&mut self, scanner: &mut Scanner) {
-        println!("The value of T is: {:?}\nscanner: {:?}", self, scanner);
-        let prev = std::mem::replace(&mut self.current, None);
-        self.previous = prev;
+        self.previous = self.current.take();
         loop {
-	    println!("The scanner is: {:?}", scanner);
             let t = scanner.scan_token(self.string);
-	    println!("The token is: {:?}", t);
             self.current = Some(t.clone());
             match t.token_type {
                 TokenType::ERROR(_) => {}
                 _ => break,
             }
-            self.error_at_current();
+            self.error_at_current("");
         }
-        println!("The value of T is: {:?}\nscanner: {:?}", self, scanner);
     }
 
-    fn error(&mut self) {
-        self.error_at(false);
+    fn lexeme(&self, token: &Token) -> &'a str {
+        self.string
+            .get(token.start..token.start + token.length)
+            .unwrap()
     }
 
-    fn error_at_current(&mut self) {
-        self.error_at(true);
+    fn error(&mut self, message: &str) {
+        self.error_at(false, message);
     }
 
-    fn error_at(&mut self, current: bool) {
-        if (current && self.current.is_some()) || (!current && self.previous.is_some()) {
-            if self.panic_mode {
-                return;
-            }
-            self.panic_mode = true;
-            let token = if current {
-                self.current.as_ref().unwrap()
-            } else {
-                self.previous.as_ref().unwrap()
-            };
-            eprint!("[line {}] Error", token.line);
-
-            match token.token_type {
-                TokenType::ERROR(_) => (),
-                TokenType::EOF => eprint!(" at end"),
-                _ => eprint!(
-                    " at '{}'",
-                    self.string
-                        .get(token.start..token.start + token.length)
-                        .unwrap()
-                ),
-            }
-            eprintln!(
-                ": {}",
-                if let TokenType::ERROR(s) = &token.token_type {
-                    s
-                } else {
-                    ""
-                }
-            );
-            self.had_error = true;
+    fn error_at_current(&mut self, message: &str) {
+        self.error_at(true, message);
+    }
+
+    /// Records an error at the current or previous token. Error tokens carry
+    /// their own message from the scanner, which takes precedence.
+    fn error_at(&mut self, current: bool, message: &str) {
+        let token = if current {
+            self.current.as_ref()
+        } else {
+            self.previous.as_ref()
+        };
+        let Some(token) = token else {
+            return;
+        };
+        if self.panic_mode {
+            return;
         }
+        self.panic_mode = true;
+        let (location, message) = match &token.token_type {
+            TokenType::ERROR(s) => (Location::Invalid, s.as_str()),
+            TokenType::EOF => (Location::End, message),
+            _ => (Location::Token, message),
+        };
+        self.diagnostics.push(Diagnostic {
+            severity: Severity::Error,
+            message: message.to_string(),
+            span: token.start..token.start + token.length,
+            line: token.line,
+            location,
+        });
+    }
+
+    fn had_error(&self) -> bool {
+        self.diagnostics
+            .iter()
+            .any(|diagnostic| diagnostic.severity == Severity::Error)
     }
 
-    fn consume(&mut self, scanner: &mut Scanner, token: TokenType) {
+    fn consume(&mut self, scanner: &mut Scanner, token: TokenType, message: &str) {
         if let Some(t) = &self.current {
-            if matches!(t.clone().token_type, token) {
+            if t.token_type == token {
                 self.advance(scanner);
                 return;
             }
         }
-        self.error_at_current()
+        self.error_at_current(message)
+    }
+
+    fn check(&self, token: TokenType) -> bool {
+        self.current.as_ref().is_some_and(|t| t.token_type == token)
+    }
+
+    fn match_token(&mut self, scanner: &mut Scanner, token: TokenType) -> bool {
+        if self.check(token) {
+            self.advance(scanner);
+            true
+        } else {
+            false
+        }
+    }
+
+    fn emit_byte(&mut self, byte: u8) {
+        let line = self.previous.as_ref().unwrap().line;
+        self.current_chunk().write_chunk(byte, line)
+    }
+
+    fn emit_bytes(&mut self, byte_1: u8, byte_2: u8) {
+        self.emit_byte(byte_1);
+        self.emit_byte(byte_2);
+    }
+
+    /// Emits an instruction with a one-byte operand, switching to the long
+    /// form with a 24-bit operand if `index` does not fit in a byte.
+    fn emit_indexed(&mut self, instruction: OpCode, index: usize) {
+        match u8::try_from(index) {
+            Ok(index) => self.emit_bytes(instruction.into(), index),
+            Err(_) => {
+                let long = instruction
+                    .long_form()
+                    .expect("only constant indices can exceed a byte");
+                self.emit_byte(long.into());
+                let [_, hi, mid, lo] = (index as u32).to_be_bytes();
+                self.emit_byte(hi);
+                self.emit_byte(mid);
+                self.emit_byte(lo);
+            }
+        }
     }
 
-    fn emit_byte(&self, byte: u8, chunk: &mut Chunk) {
-        chunk.write_chunk(byte, self.previous.as_ref().unwrap().line)
+    /// Emits a jump instruction with a placeholder offset, returning the
+    /// location of the offset so it can be filled in by `patch_jump`.
+    fn emit_jump(&mut self, instruction: OpCode) -> usize {
+        self.emit_byte(instruction.into());
+        self.emit_bytes(0xff, 0xff);
+        self.current_chunk().codes.len() - 2
     }
 
-    fn emit_bytes(&self, byte_1: u8, byte_2: u8, chunk: &mut Chunk) {
-        self.emit_byte(byte_1, chunk);
-        self.emit_byte(byte_2, chunk);
+    fn patch_jump(&mut self, offset: usize) {
+        let jump = self.current_chunk().codes.len() - offset - 2;
+        if jump > u16::MAX as usize {
+            self.error("Too much code to jump over.");
+        }
+        let [hi, lo] = (jump as u16).to_be_bytes();
+        self.current_chunk().codes[offset] = hi;
+        self.current_chunk().codes[offset + 1] = lo;
     }
 
-    fn end_compiler(&self, chunk: &mut Chunk) {
-        self.emit_byte(OpCode::OP_RETURN.into(), chunk);
-        if !self.had_error {
-            disassemble_chunk(chunk, "code")
+    fn emit_loop(&mut self, loop_start: usize) {
+        self.emit_byte(OpCode::OP_LOOP.into());
+        let offset = self.current_chunk().codes.len() - loop_start + 2;
+        if offset > u16::MAX as usize {
+            self.error("Loop body too large.");
         }
+        let [hi, lo] = (offset as u16).to_be_bytes();
+        self.emit_bytes(hi, lo);
     }
 
-    fn grouping(&mut self, scanner: &mut Scanner, chunk: &mut Chunk) {
-        self.expression(scanner, chunk);
-        self.consume(scanner, TokenType::RIGHT_PAREN);
+    fn emit_return(&mut self) {
+        // Initializers always return the instance being initialized.
+        if self.compiler().function_type == FunctionType::Initializer {
+            self.emit_bytes(OpCode::OP_GET_LOCAL.into(), 0);
+        } else {
+            self.emit_byte(OpCode::OP_NIL.into());
+        }
+        self.emit_byte(OpCode::OP_RETURN.into());
     }
 
-    fn number(&mut self, chunk: &mut Chunk) {
+    /// Finishes the innermost function and moves it onto the heap.
+    fn end_compiler(&mut self) -> (ObjRef, Vec<Upvalue>) {
+        self.emit_return();
+        // Collect while the function's constants are still rooted by its
+        // compiler, since they are not reachable once it is popped.
+        self.maybe_collect_garbage();
+        let compiler = self.compilers.pop().unwrap();
+        let function = compiler.function;
+        if !self.had_error() {
+            let name = match function.name {
+                Some(name) => self.vm.heap.as_string(name).unwrap(),
+                None => "<script>",
+            };
+            self.vm
+                .tracer
+                .on_compile_chunk(&function.chunk, name, &self.vm.heap)
+        }
+        (
+            self.vm.heap.alloc(Object::Function(function)),
+            compiler.upvalues,
+        )
+    }
+
+    fn copy_string(&mut self, chars: &str) -> ObjRef {
+        self.maybe_collect_garbage();
+        self.vm.heap.copy_string(chars)
+    }
+
+    /// Collects garbage if the heap has grown enough, treating the functions
+    /// still being compiled as roots alongside the VM's own.
+    fn maybe_collect_garbage(&mut self) {
+        if !self.vm.heap.should_collect() {
+            return;
+        }
+        self.vm.mark_roots();
+        for compiler in &self.compilers {
+            if let Some(name) = compiler.function.name {
+                self.vm.heap.mark_object(name);
+            }
+            for &value in &compiler.function.chunk.values {
+                self.vm.heap.mark_value(value);
+            }
+        }
+        self.vm.heap.collect_garbage();
+    }
+
+    fn grouping(&mut self, scanner: &mut Scanner) {
+        self.expression(scanner);
+        self.consume(
+            scanner,
+            TokenType::RIGHT_PAREN,
+            "Expect ')' after expression.",
+        );
+    }
+
+    fn number(&mut self) {
         let prev = self.previous.as_ref().unwrap();
         let num = self
             .string
@@ -127,77 +339,730 @@ impl<'a> Parser<'a> {
             .unwrap()
             .parse::<f64>()
             .unwrap();
-        self.emit_constant(num, chunk);
+        self.emit_constant(Value::Number(num));
     }
 
-    fn emit_constant(&mut self, constant: Value, chunk: &mut Chunk) {
-        self.emit_bytes(
-            OpCode::OP_CONSTANT.into(),
-            chunk.make_constant(constant),
-            chunk,
+    fn string(&mut self) {
+        let prev = self.previous.as_ref().unwrap();
+        let chars = self
+            .string
+            .get(prev.start + 1..prev.start + prev.length - 1)
+            .unwrap();
+        let string = self.copy_string(chars);
+        self.emit_constant(Value::Obj(string));
+    }
+
+    fn emit_constant(&mut self, constant: Value) {
+        let constant = self.make_constant(constant);
+        self.emit_indexed(OpCode::OP_CONSTANT, constant);
+    }
+
+    fn make_constant(&mut self, value: Value) -> usize {
+        let constant = self.current_chunk().add_constant(value);
+        if constant >= MAX_CONSTANTS {
+            self.error("Too many constants in one chunk.");
+            return 0;
+        }
+        constant
+    }
+
+    fn literal(&mut self) {
+        match self.previous.as_ref().unwrap().token_type {
+            TokenType::FALSE => self.emit_byte(OpCode::OP_FALSE.into()),
+            TokenType::NIL => self.emit_byte(OpCode::OP_NIL.into()),
+            TokenType::TRUE => self.emit_byte(OpCode::OP_TRUE.into()),
+            _ => panic!(),
+        }
+    }
+
+    fn variable(&mut self, scanner: &mut Scanner, can_assign: bool) {
+        let name = self.lexeme(self.previous.as_ref().unwrap());
+        self.named_variable(scanner, name, can_assign);
+    }
+
+    fn this(&mut self, scanner: &mut Scanner) {
+        if self.classes.is_empty() {
+            self.error("Can't use 'this' outside of a class.");
+            return;
+        }
+        self.named_variable(scanner, "this", false);
+    }
+
+    fn super_(&mut self, scanner: &mut Scanner) {
+        match self.classes.last() {
+            None => self.error("Can't use 'super' outside of a class."),
+            Some(class) if !class.has_superclass => {
+                self.error("Can't use 'super' in a class with no superclass.")
+            }
+            Some(_) => {}
+        }
+        self.consume(scanner, TokenType::DOT, "Expect '.' after 'super'.");
+        self.consume(
+            scanner,
+            TokenType::IDENTIFIER,
+            "Expect superclass method name.",
         );
+        let name = self.lexeme(self.previous.as_ref().unwrap());
+        let name = self.identifier_constant(name);
+
+        self.named_variable(scanner, "this", false);
+        if self.match_token(scanner, TokenType::LEFT_PAREN) {
+            let arg_count = self.argument_list(scanner);
+            self.named_variable(scanner, "super", false);
+            self.emit_indexed(OpCode::OP_SUPER_INVOKE, name);
+            self.emit_byte(arg_count);
+        } else {
+            self.named_variable(scanner, "super", false);
+            self.emit_indexed(OpCode::OP_GET_SUPER, name);
+        }
+    }
+
+    fn named_variable(&mut self, scanner: &mut Scanner, name: &'a str, can_assign: bool) {
+        let depth = self.compilers.len() - 1;
+        let (get_op, set_op, arg) = if let Some(slot) = self.resolve_local(depth, name) {
+            (OpCode::OP_GET_LOCAL, OpCode::OP_SET_LOCAL, slot as usize)
+        } else if let Some(upvalue) = self.resolve_upvalue(depth, name) {
+            (
+                OpCode::OP_GET_UPVALUE,
+                OpCode::OP_SET_UPVALUE,
+                upvalue as usize,
+            )
+        } else {
+            (
+                OpCode::OP_GET_GLOBAL,
+                OpCode::OP_SET_GLOBAL,
+                self.identifier_constant(name),
+            )
+        };
+        if can_assign && self.match_token(scanner, TokenType::EQUAL) {
+            self.expression(scanner);
+            self.emit_indexed(set_op, arg);
+        } else {
+            self.emit_indexed(get_op, arg);
+        }
     }
 
-    fn unary(&mut self, scanner: &mut Scanner, chunk: &mut Chunk) {
+    fn call(&mut self, scanner: &mut Scanner) {
+        let arg_count = self.argument_list(scanner);
+        self.emit_bytes(OpCode::OP_CALL.into(), arg_count);
+    }
+
+    fn argument_list(&mut self, scanner: &mut Scanner) -> u8 {
+        let mut arg_count: usize = 0;
+        if !self.check(TokenType::RIGHT_PAREN) {
+            loop {
+                self.expression(scanner);
+                if arg_count == 255 {
+                    self.error("Can't have more than 255 arguments.");
+                }
+                arg_count += 1;
+                if !self.match_token(scanner, TokenType::COMMA) {
+                    break;
+                }
+            }
+        }
+        self.consume(
+            scanner,
+            TokenType::RIGHT_PAREN,
+            "Expect ')' after arguments.",
+        );
+        arg_count as u8
+    }
+
+    fn dot(&mut self, scanner: &mut Scanner, can_assign: bool) {
+        self.consume(
+            scanner,
+            TokenType::IDENTIFIER,
+            "Expect property name after '.'.",
+        );
+        let name = self.lexeme(self.previous.as_ref().unwrap());
+        let name = self.identifier_constant(name);
+        if can_assign && self.match_token(scanner, TokenType::EQUAL) {
+            self.expression(scanner);
+            self.emit_indexed(OpCode::OP_SET_PROPERTY, name);
+        } else if self.match_token(scanner, TokenType::LEFT_PAREN) {
+            // Calling a method directly skips creating a bound method.
+            let arg_count = self.argument_list(scanner);
+            self.emit_indexed(OpCode::OP_INVOKE, name);
+            self.emit_byte(arg_count);
+        } else {
+            self.emit_indexed(OpCode::OP_GET_PROPERTY, name);
+        }
+    }
+
+    fn unary(&mut self, scanner: &mut Scanner) {
         let op_type = self.previous.as_ref().unwrap().token_type.clone();
-        self.parse_precedence(scanner, chunk, Precedence::UNARY);
+        self.parse_precedence(scanner, Precedence::UNARY);
 
         match op_type {
-            TokenType::MINUS => self.emit_byte(OpCode::OP_NEGATE.into(), chunk),
+            TokenType::BANG => self.emit_byte(OpCode::OP_NOT.into()),
+            TokenType::MINUS => self.emit_byte(OpCode::OP_NEGATE.into()),
             _ => panic!(),
         }
     }
 
-    fn binary(&mut self, scanner: &mut Scanner, chunk: &mut Chunk) {
+    fn binary(&mut self, scanner: &mut Scanner) {
         let op_type = self.previous.as_ref().unwrap().token_type.clone();
         let (_, _, precedence) = get_rule(op_type.clone());
-        self.parse_precedence(
-            scanner,
-            chunk,
-            Precedence::try_from(1 + precedence as u8).unwrap(),
-        );
+        self.parse_precedence(scanner, Precedence::try_from(1 + precedence as u8).unwrap());
 
         match op_type {
-            TokenType::PLUS => self.emit_byte(OpCode::OP_ADD.into(), chunk),
-            TokenType::MINUS => self.emit_byte(OpCode::OP_SUBTRACT.into(), chunk),
-            TokenType::STAR => self.emit_byte(OpCode::OP_MULTIPLY.into(), chunk),
-            TokenType::SLASH => self.emit_byte(OpCode::OP_DIVIDE.into(), chunk),
+            TokenType::BANG_EQUAL => {
+                self.emit_bytes(OpCode::OP_EQUAL.into(), OpCode::OP_NOT.into())
+            }
+            TokenType::EQUAL_EQUAL => self.emit_byte(OpCode::OP_EQUAL.into()),
+            TokenType::GREATER => self.emit_byte(OpCode::OP_GREATER.into()),
+            TokenType::GREATER_EQUAL => {
+                self.emit_bytes(OpCode::OP_LESS.into(), OpCode::OP_NOT.into())
+            }
+            TokenType::LESS => self.emit_byte(OpCode::OP_LESS.into()),
+            TokenType::LESS_EQUAL => {
+                self.emit_bytes(OpCode::OP_GREATER.into(), OpCode::OP_NOT.into())
+            }
+            TokenType::PLUS => self.emit_byte(OpCode::OP_ADD.into()),
+            TokenType::MINUS => self.emit_byte(OpCode::OP_SUBTRACT.into()),
+            TokenType::STAR => self.emit_byte(OpCode::OP_MULTIPLY.into()),
+            TokenType::SLASH => self.emit_byte(OpCode::OP_DIVIDE.into()),
             _ => panic!(),
         }
     }
 
-    fn expression(&mut self, scanner: &mut Scanner, chunk: &mut Chunk) {
-        self.parse_precedence(scanner, chunk, Precedence::ASSIGNMENT);
+    fn and(&mut self, scanner: &mut Scanner) {
+        let end_jump = self.emit_jump(OpCode::OP_JUMP_IF_FALSE);
+        self.emit_byte(OpCode::OP_POP.into());
+        self.parse_precedence(scanner, Precedence::AND);
+        self.patch_jump(end_jump);
+    }
+
+    fn or(&mut self, scanner: &mut Scanner) {
+        let else_jump = self.emit_jump(OpCode::OP_JUMP_IF_FALSE);
+        let end_jump = self.emit_jump(OpCode::OP_JUMP);
+        self.patch_jump(else_jump);
+        self.emit_byte(OpCode::OP_POP.into());
+        self.parse_precedence(scanner, Precedence::OR);
+        self.patch_jump(end_jump);
+    }
+
+    fn expression(&mut self, scanner: &mut Scanner) {
+        self.parse_precedence(scanner, Precedence::ASSIGNMENT);
+    }
+
+    fn declaration(&mut self, scanner: &mut Scanner) {
+        if self.match_token(scanner, TokenType::CLASS) {
+            self.class_declaration(scanner);
+        } else if self.match_token(scanner, TokenType::FUN) {
+            self.fun_declaration(scanner);
+        } else if self.match_token(scanner, TokenType::VAR) {
+            self.var_declaration(scanner);
+        } else {
+            self.statement(scanner);
+        }
+        if self.panic_mode {
+            self.synchronize(scanner);
+        }
+    }
+
+    /// Skips tokens until a likely statement boundary, so that one mistake
+    /// does not cascade into a flood of errors.
+    fn synchronize(&mut self, scanner: &mut Scanner) {
+        self.panic_mode = false;
+        while !self.check(TokenType::EOF) {
+            if self
+                .previous
+                .as_ref()
+                .is_some_and(|t| t.token_type == TokenType::SEMICOLON)
+            {
+                return;
+            }
+            match self.current.as_ref().unwrap().token_type {
+                TokenType::CLASS
+                | TokenType::FUN
+                | TokenType::VAR
+                | TokenType::FOR
+                | TokenType::IF
+                | TokenType::WHILE
+                | TokenType::PRINT
+                | TokenType::RETURN => return,
+                _ => self.advance(scanner),
+            }
+        }
     }
 
-    fn parse_precedence(
-        &mut self,
-        scanner: &mut Scanner,
-        chunk: &mut Chunk,
-        precedence: Precedence,
-    ) {
+    fn class_declaration(&mut self, scanner: &mut Scanner) {
+        self.consume(scanner, TokenType::IDENTIFIER, "Expect class name.");
+        let class_name = self.lexeme(self.previous.as_ref().unwrap());
+        let name_constant = self.identifier_constant(class_name);
+        self.declare_variable();
+
+        self.emit_indexed(OpCode::OP_CLASS, name_constant);
+        self.define_variable(name_constant);
+        self.classes.push(ClassCompiler {
+            has_superclass: false,
+        });
+
+        if self.match_token(scanner, TokenType::LESS) {
+            self.consume(scanner, TokenType::IDENTIFIER, "Expect superclass name.");
+            let superclass_name = self.lexeme(self.previous.as_ref().unwrap());
+            self.variable(scanner, false);
+            if class_name == superclass_name {
+                self.error("A class can't inherit from itself.");
+            }
+
+            // The superclass is kept in a local named `super`, which the
+            // methods capture as an upvalue.
+            self.begin_scope();
+            self.add_local("super");
+            self.define_variable(0);
+
+            self.named_variable(scanner, class_name, false);
+            self.emit_byte(OpCode::OP_INHERIT.into());
+            self.classes.last_mut().unwrap().has_superclass = true;
+        }
+
+        // Keep the class on the stack while its methods are bound to it.
+        self.named_variable(scanner, class_name, false);
+        self.consume(
+            scanner,
+            TokenType::LEFT_BRACE,
+            "Expect '{' before class body.",
+        );
+        while !self.check(TokenType::RIGHT_BRACE) && !self.check(TokenType::EOF) {
+            self.method(scanner);
+        }
+        self.consume(
+            scanner,
+            TokenType::RIGHT_BRACE,
+            "Expect '}' after class body.",
+        );
+        self.emit_byte(OpCode::OP_POP.into());
+
+        if self.classes.pop().unwrap().has_superclass {
+            self.end_scope();
+        }
+    }
+
+    fn method(&mut self, scanner: &mut Scanner) {
+        self.consume(scanner, TokenType::IDENTIFIER, "Expect method name.");
+        let name = self.lexeme(self.previous.as_ref().unwrap());
+        let constant = self.identifier_constant(name);
+        let function_type = if name == "init" {
+            FunctionType::Initializer
+        } else {
+            FunctionType::Method
+        };
+        self.function(scanner, function_type);
+        self.emit_indexed(OpCode::OP_METHOD, constant);
+    }
+
+    fn fun_declaration(&mut self, scanner: &mut Scanner) {
+        let global = self.parse_variable(scanner, "Expect function name.");
+        // A function may refer to itself, so it is usable before its body
+        // has been compiled.
+        self.mark_initialized();
+        self.function(scanner, FunctionType::Function);
+        self.define_variable(global);
+    }
+
+    fn function(&mut self, scanner: &mut Scanner, function_type: FunctionType) {
+        let name = self.lexeme(self.previous.as_ref().unwrap());
+        let name = self.copy_string(name);
+        self.compilers
+            .push(Compiler::new(function_type, Some(name)));
+        self.begin_scope();
+
+        self.consume(
+            scanner,
+            TokenType::LEFT_PAREN,
+            "Expect '(' after function name.",
+        );
+        if !self.check(TokenType::RIGHT_PAREN) {
+            loop {
+                self.compiler_mut().function.arity += 1;
+                if self.compiler().function.arity > 255 {
+                    self.error_at_current("Can't have more than 255 parameters.");
+                }
+                let constant = self.parse_variable(scanner, "Expect parameter name.");
+                self.define_variable(constant);
+                if !self.match_token(scanner, TokenType::COMMA) {
+                    break;
+                }
+            }
+        }
+        self.consume(
+            scanner,
+            TokenType::RIGHT_PAREN,
+            "Expect ')' after parameters.",
+        );
+        self.consume(
+            scanner,
+            TokenType::LEFT_BRACE,
+            "Expect '{' before function body.",
+        );
+        self.block(scanner);
+
+        let (function, upvalues) = self.end_compiler();
+        let constant = self.make_constant(Value::Obj(function));
+        self.emit_indexed(OpCode::OP_CLOSURE, constant);
+        let [hi, lo] = (upvalues.len() as u16).to_be_bytes();
+        self.emit_bytes(hi, lo);
+        for upvalue in upvalues {
+            self.emit_bytes(upvalue.is_local.into(), upvalue.index);
+        }
+    }
+
+    fn var_declaration(&mut self, scanner: &mut Scanner) {
+        let global = self.parse_variable(scanner, "Expect variable name.");
+        if self.match_token(scanner, TokenType::EQUAL) {
+            self.expression(scanner);
+        } else {
+            self.emit_byte(OpCode::OP_NIL.into());
+        }
+        self.consume(
+            scanner,
+            TokenType::SEMICOLON,
+            "Expect ';' after variable declaration.",
+        );
+        self.define_variable(global);
+    }
+
+    fn parse_variable(&mut self, scanner: &mut Scanner, message: &str) -> usize {
+        self.consume(scanner, TokenType::IDENTIFIER, message);
+        self.declare_variable();
+        if self.compiler().scope_depth > 0 {
+            return 0;
+        }
+        let name = self.lexeme(self.previous.as_ref().unwrap());
+        self.identifier_constant(name)
+    }
+
+    /// Records a local variable in the current scope. Globals are late bound,
+    /// so they aren't declared here.
+    fn declare_variable(&mut self) {
+        if self.compiler().scope_depth == 0 {
+            return;
+        }
+        let name = self.lexeme(self.previous.as_ref().unwrap());
+        let already_declared = self
+            .compiler()
+            .locals
+            .iter()
+            .rev()
+            .take_while(|local| local.depth.is_none_or(|d| d >= self.compiler().scope_depth))
+            .any(|local| local.name == name);
+        if already_declared {
+            self.error("Already a variable with this name in this scope.");
+        }
+        self.add_local(name);
+    }
+
+    fn add_local(&mut self, name: &'a str) {
+        if self.compiler_mut().locals.len() == UINT8_COUNT {
+            self.error("Too many local variables in function.");
+            return;
+        }
+        self.compiler_mut().locals.push(Local {
+            name,
+            depth: None,
+            is_captured: false,
+        });
+    }
+
+    /// Looks up a local variable in the compiler at `depth` in the compiler
+    /// stack, returning its stack slot.
+    fn resolve_local(&mut self, depth: usize, name: &str) -> Option<u8> {
+        let (slot, local) = self.compilers[depth]
+            .locals
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(_, local)| local.name == name)?;
+        if local.depth.is_none() {
+            self.error("Can't read local variable in its own initializer.");
+        }
+        Some(slot as u8)
+    }
+
+    /// Looks up a variable captured from an enclosing function, threading
+    /// the upvalue through every function in between.
+    fn resolve_upvalue(&mut self, depth: usize, name: &str) -> Option<u8> {
+        if depth == 0 {
+            return None;
+        }
+        if let Some(local) = self.resolve_local(depth - 1, name) {
+            self.compilers[depth - 1].locals[local as usize].is_captured = true;
+            return Some(self.add_upvalue(depth, local, true));
+        }
+        let upvalue = self.resolve_upvalue(depth - 1, name)?;
+        Some(self.add_upvalue(depth, upvalue, false))
+    }
+
+    fn add_upvalue(&mut self, depth: usize, index: u8, is_local: bool) -> u8 {
+        let upvalue = Upvalue { index, is_local };
+        let upvalues = &self.compilers[depth].upvalues;
+        if let Some(existing) = upvalues.iter().position(|u| *u == upvalue) {
+            return existing as u8;
+        }
+        if upvalues.len() == UINT8_COUNT {
+            self.error("Too many closure variables in function.");
+            return 0;
+        }
+        self.compilers[depth].upvalues.push(upvalue);
+        (self.compilers[depth].upvalues.len() - 1) as u8
+    }
+
+    fn mark_initialized(&mut self) {
+        let compiler = self.compiler_mut();
+        if compiler.scope_depth == 0 {
+            return;
+        }
+        if let Some(local) = compiler.locals.last_mut() {
+            local.depth = Some(compiler.scope_depth);
+        }
+    }
+
+    /// Stores an identifier in the constant table, so that instructions
+    /// looking up globals and properties can refer to it by index.
+    fn identifier_constant(&mut self, name: &str) -> usize {
+        let name = self.copy_string(name);
+        self.make_constant(Value::Obj(name))
+    }
+
+    fn define_variable(&mut self, global: usize) {
+        if self.compiler().scope_depth > 0 {
+            self.mark_initialized();
+            return;
+        }
+        self.emit_indexed(OpCode::OP_DEFINE_GLOBAL, global);
+    }
+
+    fn statement(&mut self, scanner: &mut Scanner) {
+        if self.match_token(scanner, TokenType::PRINT) {
+            self.print_statement(scanner);
+        } else if self.match_token(scanner, TokenType::FOR) {
+            self.for_statement(scanner);
+        } else if self.match_token(scanner, TokenType::IF) {
+            self.if_statement(scanner);
+        } else if self.match_token(scanner, TokenType::RETURN) {
+            self.return_statement(scanner);
+        } else if self.match_token(scanner, TokenType::WHILE) {
+            self.while_statement(scanner);
+        } else if self.match_token(scanner, TokenType::LEFT_BRACE) {
+            self.begin_scope();
+            self.block(scanner);
+            self.end_scope();
+        } else {
+            self.expression_statement(scanner);
+        }
+    }
+
+    fn block(&mut self, scanner: &mut Scanner) {
+        while !self.check(TokenType::RIGHT_BRACE) && !self.check(TokenType::EOF) {
+            self.declaration(scanner);
+        }
+        self.consume(scanner, TokenType::RIGHT_BRACE, "Expect '}' after block.");
+    }
+
+    fn begin_scope(&mut self) {
+        self.compiler_mut().scope_depth += 1;
+    }
+
+    fn end_scope(&mut self) {
+        self.compiler_mut().scope_depth -= 1;
+        while self
+            .compiler()
+            .locals
+            .last()
+            .is_some_and(|local| local.depth.is_none_or(|d| d > self.compiler().scope_depth))
+        {
+            let local = self.compiler_mut().locals.pop().unwrap();
+            if local.is_captured {
+                self.emit_byte(OpCode::OP_CLOSE_UPVALUE.into());
+            } else {
+                self.emit_byte(OpCode::OP_POP.into());
+            }
+        }
+    }
+
+    fn if_statement(&mut self, scanner: &mut Scanner) {
+        self.consume(scanner, TokenType::LEFT_PAREN, "Expect '(' after 'if'.");
+        self.expression(scanner);
+        self.consume(
+            scanner,
+            TokenType::RIGHT_PAREN,
+            "Expect ')' after condition.",
+        );
+
+        let then_jump = self.emit_jump(OpCode::OP_JUMP_IF_FALSE);
+        self.emit_byte(OpCode::OP_POP.into());
+        self.statement(scanner);
+        let else_jump = self.emit_jump(OpCode::OP_JUMP);
+
+        self.patch_jump(then_jump);
+        self.emit_byte(OpCode::OP_POP.into());
+        if self.match_token(scanner, TokenType::ELSE) {
+            self.statement(scanner);
+        }
+        self.patch_jump(else_jump);
+    }
+
+    fn while_statement(&mut self, scanner: &mut Scanner) {
+        let loop_start = self.current_chunk().codes.len();
+        self.consume(scanner, TokenType::LEFT_PAREN, "Expect '(' after 'while'.");
+        self.expression(scanner);
+        self.consume(
+            scanner,
+            TokenType::RIGHT_PAREN,
+            "Expect ')' after condition.",
+        );
+
+        let exit_jump = self.emit_jump(OpCode::OP_JUMP_IF_FALSE);
+        self.emit_byte(OpCode::OP_POP.into());
+        self.statement(scanner);
+        self.emit_loop(loop_start);
+
+        self.patch_jump(exit_jump);
+        self.emit_byte(OpCode::OP_POP.into());
+    }
+
+    fn for_statement(&mut self, scanner: &mut Scanner) {
+        self.begin_scope();
+        self.consume(scanner, TokenType::LEFT_PAREN, "Expect '(' after 'for'.");
+        if self.match_token(scanner, TokenType::SEMICOLON) {
+            // No initializer.
+        } else if self.match_token(scanner, TokenType::VAR) {
+            self.var_declaration(scanner);
+        } else {
+            self.expression_statement(scanner);
+        }
+
+        let mut loop_start = self.current_chunk().codes.len();
+        let mut exit_jump = None;
+        if !self.match_token(scanner, TokenType::SEMICOLON) {
+            self.expression(scanner);
+            self.consume(
+                scanner,
+                TokenType::SEMICOLON,
+                "Expect ';' after loop condition.",
+            );
+            exit_jump = Some(self.emit_jump(OpCode::OP_JUMP_IF_FALSE));
+            self.emit_byte(OpCode::OP_POP.into());
+        }
+
+        if !self.match_token(scanner, TokenType::RIGHT_PAREN) {
+            // The increment runs after the body, so jump over it and loop
+            // back to it from the end of the body.
+            let body_jump = self.emit_jump(OpCode::OP_JUMP);
+            let increment_start = self.current_chunk().codes.len();
+            self.expression(scanner);
+            self.emit_byte(OpCode::OP_POP.into());
+            self.consume(
+                scanner,
+                TokenType::RIGHT_PAREN,
+                "Expect ')' after for clauses.",
+            );
+
+            self.emit_loop(loop_start);
+            loop_start = increment_start;
+            self.patch_jump(body_jump);
+        }
+
+        self.statement(scanner);
+        self.emit_loop(loop_start);
+
+        if let Some(exit_jump) = exit_jump {
+            self.patch_jump(exit_jump);
+            self.emit_byte(OpCode::OP_POP.into());
+        }
+        self.end_scope();
+    }
+
+    fn return_statement(&mut self, scanner: &mut Scanner) {
+        if self.compiler().function_type == FunctionType::Script {
+            self.error("Can't return from top-level code.");
+        }
+        if self.match_token(scanner, TokenType::SEMICOLON) {
+            self.emit_return();
+        } else {
+            if self.compiler().function_type == FunctionType::Initializer {
+                self.error("Can't return a value from an initializer.");
+            }
+            self.expression(scanner);
+            self.consume(
+                scanner,
+                TokenType::SEMICOLON,
+                "Expect ';' after return value.",
+            );
+            self.emit_byte(OpCode::OP_RETURN.into());
+        }
+    }
+
+    fn print_statement(&mut self, scanner: &mut Scanner) {
+        self.expression(scanner);
+        self.consume(scanner, TokenType::SEMICOLON, "Expect ';' after value.");
+        self.emit_byte(OpCode::OP_PRINT.into());
+    }
+
+    fn expression_statement(&mut self, scanner: &mut Scanner) {
+        self.expression(scanner);
+        if self.repl && self.compilers.len() == 1 && self.check(TokenType::EOF) {
+            self.emit_byte(OpCode::OP_PRINT.into());
+            return;
+        }
+        self.consume(
+            scanner,
+            TokenType::SEMICOLON,
+            "Expect ';' after expression.",
+        );
+        self.emit_byte(OpCode::OP_POP.into());
+    }
+
+    fn parse_precedence(&mut self, scanner: &mut Scanner, precedence: Precedence) {
         self.advance(scanner);
-        println!("{:?}", self.previous);
         let (prefix, _, _) = get_rule(self.previous.as_ref().unwrap().token_type.clone());
+        let can_assign = precedence.clone() as u8 <= Precedence::ASSIGNMENT as u8;
         match prefix {
-            Some(p) => self.prefix_rule(scanner, chunk, p),
-            None => self.error(),
+            Some(p) => self.prefix_rule(scanner, p, can_assign),
+            None => {
+                self.error("Expect expression.");
+                return;
+            }
         }
         let pu8 = precedence as u8;
         while pu8 <= get_rule(self.current.as_ref().unwrap().token_type.clone()).2 as u8 {
             self.advance(scanner);
-            let (_, infix, _) = get_rule(self.current.as_ref().unwrap().token_type.clone());
-            if infix {
-                self.binary(scanner, chunk)
+            let (_, infix, _) = get_rule(self.previous.as_ref().unwrap().token_type.clone());
+            if let Some(i) = infix {
+                self.infix_rule(scanner, i, can_assign);
             }
         }
+
+        if can_assign && self.match_token(scanner, TokenType::EQUAL) {
+            self.error("Invalid assignment target.");
+        }
+    }
+
+    fn prefix_rule(&mut self, scanner: &mut Scanner, rule: RuleType, can_assign: bool) {
+        match rule {
+            RuleType::Grouping => self.grouping(scanner),
+            RuleType::Unary => self.unary(scanner),
+            RuleType::Number => self.number(),
+            RuleType::Literal => self.literal(),
+            RuleType::String => self.string(),
+            RuleType::Variable => self.variable(scanner, can_assign),
+            RuleType::This => self.this(scanner),
+            RuleType::Super => self.super_(scanner),
+            _ => unreachable!(),
+        }
     }
 
-    fn prefix_rule(&mut self, scanner: &mut Scanner, chunk: &mut Chunk, rule: RuleType) {
+    fn infix_rule(&mut self, scanner: &mut Scanner, rule: RuleType, can_assign: bool) {
         match rule {
-            RuleType::Grouping => self.grouping(scanner, chunk),
-            RuleType::Unary => self.unary(scanner, chunk),
-            RuleType::Number => self.number(chunk),
+            RuleType::Binary => self.binary(scanner),
+            RuleType::And => self.and(scanner),
+            RuleType::Or => self.or(scanner),
+            RuleType::Call => self.call(scanner),
+            RuleType::Dot => self.dot(scanner, can_assign),
+            _ => unreachable!(),
         }
     }
 }
@@ -205,19 +1070,54 @@ enum RuleType {
     Grouping,
     Unary,
     Number,
+    Literal,
+    String,
+    Variable,
+    This,
+    Super,
+    Binary,
+    And,
+    Or,
+    Call,
+    Dot,
 }
 
-type ParseRule = (Option<RuleType>, bool, Precedence);
+type ParseRule = (Option<RuleType>, Option<RuleType>, Precedence);
 
 fn get_rule(token: TokenType) -> ParseRule {
     match token {
-        TokenType::LEFT_PAREN => (Some(RuleType::Grouping), false, Precedence::NONE),
-        TokenType::MINUS => (Some(RuleType::Unary), true, Precedence::TERM),
-        TokenType::PLUS => (None, true, Precedence::TERM),
-        TokenType::SLASH => (None, true, Precedence::FACTOR),
-        TokenType::STAR => (None, true, Precedence::FACTOR),
-        TokenType::NUMBER => (Some(RuleType::Number), false, Precedence::NONE),
-        _ => (None, false, Precedence::NONE),
+        TokenType::LEFT_PAREN => (
+            Some(RuleType::Grouping),
+            Some(RuleType::Call),
+            Precedence::CALL,
+        ),
+        TokenType::MINUS => (
+            Some(RuleType::Unary),
+            Some(RuleType::Binary),
+            Precedence::TERM,
+        ),
+        TokenType::PLUS => (None, Some(RuleType::Binary), Precedence::TERM),
+        TokenType::SLASH => (None, Some(RuleType::Binary), Precedence::FACTOR),
+        TokenType::STAR => (None, Some(RuleType::Binary), Precedence::FACTOR),
+        TokenType::BANG => (Some(RuleType::Unary), None, Precedence::NONE),
+        TokenType::BANG_EQUAL => (None, Some(RuleType::Binary), Precedence::EQUALITY),
+        TokenType::EQUAL_EQUAL => (None, Some(RuleType::Binary), Precedence::EQUALITY),
+        TokenType::GREATER => (None, Some(RuleType::Binary), Precedence::COMPARISON),
+        TokenType::GREATER_EQUAL => (None, Some(RuleType::Binary), Precedence::COMPARISON),
+        TokenType::LESS => (None, Some(RuleType::Binary), Precedence::COMPARISON),
+        TokenType::LESS_EQUAL => (None, Some(RuleType::Binary), Precedence::COMPARISON),
+        TokenType::IDENTIFIER => (Some(RuleType::Variable), None, Precedence::NONE),
+        TokenType::STRING => (Some(RuleType::String), None, Precedence::NONE),
+        TokenType::NUMBER => (Some(RuleType::Number), None, Precedence::NONE),
+        TokenType::FALSE => (Some(RuleType::Literal), None, Precedence::NONE),
+        TokenType::NIL => (Some(RuleType::Literal), None, Precedence::NONE),
+        TokenType::TRUE => (Some(RuleType::Literal), None, Precedence::NONE),
+        TokenType::DOT => (None, Some(RuleType::Dot), Precedence::CALL),
+        TokenType::THIS => (Some(RuleType::This), None, Precedence::NONE),
+        TokenType::SUPER => (Some(RuleType::Super), None, Precedence::NONE),
+        TokenType::AND => (None, Some(RuleType::And), Precedence::AND),
+        TokenType::OR => (None, Some(RuleType::Or), Precedence::OR),
+        _ => (None, None, Precedence::NONE),
     }
 }
 
@@ -237,13 +1137,97 @@ enum Precedence {
     PRIMARY,
 }
 
-pub fn compile(source: &String, chunk: &mut Chunk) -> bool {
+/// Compiles `source` into the function for its top-level script, or returns
+/// every problem found.
+pub fn compile(source: &str, vm: &mut VM) -> Result<ObjRef, Vec<Diagnostic>> {
+    compile_source(source, vm, false)
+}
+
+/// Like `compile`, but a top-level expression left without a semicolon at the
+/// end of the input is printed, as a REPL does.
+pub fn compile_repl(source: &str, vm: &mut VM) -> Result<ObjRef, Vec<Diagnostic>> {
+    compile_source(source, vm, true)
+}
+
+fn compile_source(source: &str, vm: &mut VM, repl: bool) -> Result<ObjRef, Vec<Diagnostic>> {
     let mut scanner = Scanner::new();
-    let mut parser = Parser::new(&source);
-    let mut line: Option<usize> = None;
-    // scanner.advance(source);
-    parser.expression(&mut scanner, chunk);
-    parser.consume(&mut scanner, TokenType::EOF);
-    parser.end_compiler(chunk);
-    true
+    let mut parser = Parser::new(source, vm);
+    parser.repl = repl;
+    parser.advance(&mut scanner);
+    while !parser.match_token(&mut scanner, TokenType::EOF) {
+        parser.declaration(&mut scanner);
+    }
+    let (function, _) = parser.end_compiler();
+    if parser.had_error() {
+        Err(parser.diagnostics)
+    } else {
+        Ok(function)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{harness::SharedBuffer, memory::Heap};
+
+    /// Every opcode in `function` and the functions nested inside it.
+    fn opcodes(function: ObjRef, heap: &Heap, found: &mut Vec<OpCode>) {
+        let chunk = &heap.as_function(function).chunk;
+        for (_, _, instruction) in chunk.instructions() {
+            found.extend(instruction.opcode());
+        }
+        for value in &chunk.values {
+            if let Value::Obj(obj) = *value {
+                if let Object::Function(_) = heap.get(obj) {
+                    opcodes(obj, heap, found);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn constants_past_256_use_long_instructions() {
+        // Each global takes two constants, its name and its value, so the
+        // declarations after these fill the script's table past 256.
+        let mut source: String = (0..200).map(|i| format!("var g{i} = {i}.5;\n")).collect();
+        // The same for a function, so the closure it makes is long too.
+        source.push_str("fun counter() {\n  var count = 0;\n");
+        source.extend((0..300).map(|i| format!("  {i}.5;\n")));
+        source.push_str("  fun increment() { count = count + 1; return count; }\n");
+        source.push_str("  return increment;\n}\n");
+        source.push_str(
+            "class Point {\n  init(x) { this.x = x; }\n  get() { return this.x; }\n}\n\
+             var p = Point(g100);\n\
+             p.y = g199;\n\
+             g0 = p.y + p.get();\n\
+             var next = counter();\n\
+             next();\n\
+             print next();\n\
+             print g0;\n",
+        );
+
+        let mut vm = VM::new();
+        let function = compile(&source, &mut vm).unwrap();
+        let mut found = Vec::new();
+        opcodes(function, &vm.heap, &mut found);
+        for op in [
+            OpCode::OP_CONSTANT_LONG,
+            OpCode::OP_DEFINE_GLOBAL_LONG,
+            OpCode::OP_GET_GLOBAL_LONG,
+            OpCode::OP_SET_GLOBAL_LONG,
+            OpCode::OP_CLOSURE_LONG,
+            OpCode::OP_CLASS_LONG,
+            OpCode::OP_METHOD_LONG,
+            OpCode::OP_GET_PROPERTY_LONG,
+            OpCode::OP_SET_PROPERTY_LONG,
+            OpCode::OP_INVOKE_LONG,
+        ] {
+            assert!(found.contains(&op), "{:?} was not emitted", op);
+        }
+
+        let out = SharedBuffer::default();
+        vm.set_output(Box::new(out.clone()));
+        vm.interpret(&source).unwrap();
+        assert_eq!(out.contents(), "2\n300\n");
+    }
 }
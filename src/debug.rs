@@ -1,50 +1,133 @@
+use std::io::{self, Write};
+
 use crate::{
-    chunk::{Chunk, OpCode},
+    chunk::Chunk,
+    instruction::{CapturedUpvalues, Instruction},
+    memory::Heap,
     value::print_value,
 };
 
-pub fn disassemble_chunk(chunk: &Chunk, name: &str) {
-    println!("== {} ==", name);
+pub fn disassemble_chunk(
+    out: &mut dyn Write,
+    chunk: &Chunk,
+    name: &str,
+    heap: &Heap,
+) -> io::Result<()> {
+    writeln!(out, "== {} ==", name)?;
     let mut offset = 0;
     while offset < chunk.codes.len() {
-        offset = disassemble_instruction(chunk, offset);
+        offset = disassemble_instruction(out, chunk, offset, heap)?;
     }
+    Ok(())
 }
 
-pub fn disassemble_instruction(chunk: &Chunk, offset: usize) -> usize {
-    print!("{:04} ", offset);
-    if offset > 0 && chunk.lines[offset] == chunk.lines[offset - 1] {
-        print!("   | ")
+pub fn disassemble_instruction(
+    out: &mut dyn Write,
+    chunk: &Chunk,
+    offset: usize,
+    heap: &Heap,
+) -> io::Result<usize> {
+    write!(out, "{:04} ", offset)?;
+    let line = chunk.line_at(offset);
+    if offset > 0 && line == chunk.line_at(offset - 1) {
+        write!(out, "   | ")?;
     } else {
-        print!("{:>4} ", chunk.lines[offset])
+        write!(out, "{:>4} ", line)?;
     }
-    let instruction = chunk.codes[offset];
-    if let Ok(c) = OpCode::try_from(instruction) {
-        match c {
-            OpCode::OP_RETURN => simple_instruction("OP_RETURN", offset),
-            OpCode::OP_NEGATE => simple_instruction("OP_NEGATE", offset),
-            OpCode::OP_CONSTANT => constant_instruction("OP_CONSTANT", chunk, offset),
-	    OpCode::OP_ADD => simple_instruction("OP_ADD", offset),
-	    OpCode::OP_MULTIPLY => simple_instruction("OP_MULTIPLY", offset),
-	    OpCode::OP_DIVIDE => simple_instruction("OP_DIVIDE", offset),
-	    OpCode::OP_SUBTRACT => simple_instruction("OP_SUBTRACT", offset),
+
+    let (instruction, next) = chunk.instruction_at(offset);
+    let name = instruction.opcode().map_or("", |op| op.info().name);
+    match instruction {
+        Instruction::Constant(index)
+        | Instruction::DefineGlobal(index)
+        | Instruction::GetGlobal(index)
+        | Instruction::SetGlobal(index)
+        | Instruction::Class(index)
+        | Instruction::GetProperty(index)
+        | Instruction::SetProperty(index)
+        | Instruction::Method(index)
+        | Instruction::GetSuper(index)
+        | Instruction::ConstantLong(index)
+        | Instruction::DefineGlobalLong(index)
+        | Instruction::GetGlobalLong(index)
+        | Instruction::SetGlobalLong(index)
+        | Instruction::ClassLong(index)
+        | Instruction::GetPropertyLong(index)
+        | Instruction::SetPropertyLong(index)
+        | Instruction::MethodLong(index)
+        | Instruction::GetSuperLong(index) => {
+            write!(out, "{:<16} {:>4} '", name, index)?;
+            print_value(out, &chunk.values[index], heap)?;
+            writeln!(out, "'")?;
         }
-    } else {
-        println!("Unknown opcode {}", instruction);
-        offset + 1
+        Instruction::GetLocal(slot)
+        | Instruction::SetLocal(slot)
+        | Instruction::Call(slot)
+        | Instruction::GetUpvalue(slot)
+        | Instruction::SetUpvalue(slot) => writeln!(out, "{:<16} {:>4}", name, slot)?,
+        // Forward jumps are relative to the end of the instruction, loops
+        // jump backwards.
+        Instruction::Jump(jump) | Instruction::JumpIfFalse(jump) => writeln!(
+            out,
+            "{:<16} {:04} -> {:04}",
+            name,
+            offset,
+            next + jump as usize
+        )?,
+        Instruction::Loop(jump) => writeln!(
+            out,
+            "{:<16} {:04} -> {:04}",
+            name,
+            offset,
+            next - jump as usize
+        )?,
+        Instruction::Invoke {
+            name: index,
+            arg_count,
+        }
+        | Instruction::SuperInvoke {
+            name: index,
+            arg_count,
+        }
+        | Instruction::InvokeLong {
+            name: index,
+            arg_count,
+        }
+        | Instruction::SuperInvokeLong {
+            name: index,
+            arg_count,
+        } => {
+            write!(out, "{:<16} ({} args) {:>4} '", name, arg_count, index)?;
+            print_value(out, &chunk.values[index], heap)?;
+            writeln!(out, "'")?;
+        }
+        Instruction::Closure { function, upvalues }
+        | Instruction::ClosureLong { function, upvalues } => {
+            write!(out, "{:<16} {:>4} ", name, function)?;
+            print_value(out, &chunk.values[function], heap)?;
+            writeln!(out)?;
+            closure_upvalues(out, chunk, upvalues)?;
+        }
+        Instruction::Unknown(byte) => writeln!(out, "Unknown opcode {}", byte)?,
+        _ => writeln!(out, "{}", name)?,
     }
+    Ok(next)
 }
 
-fn simple_instruction(text: &str, offset: usize) -> usize {
-    println!("{}", text);
-    offset + 1
-}
-
-fn constant_instruction(text: &str, chunk: &Chunk, offset: usize) -> usize {
-    let constant_loc = chunk.codes[offset + 1];
-    let constant = chunk.values[constant_loc as usize];
-    print!("{:<16} {:>4} '", text, constant_loc);
-    print_value(&constant);
-    println!("'");
-    offset + 2
+/// Prints a line for each variable a closure captures.
+fn closure_upvalues(
+    out: &mut dyn Write,
+    chunk: &Chunk,
+    upvalues: CapturedUpvalues,
+) -> io::Result<()> {
+    for (index, upvalue) in upvalues.iter(chunk).enumerate() {
+        writeln!(
+            out,
+            "{:04}    |                     {} {}",
+            upvalues.offset + 2 * index,
+            if upvalue.is_local { "local" } else { "upvalue" },
+            upvalue.index
+        )?;
+    }
+    Ok(())
 }
@@ -0,0 +1,56 @@
+use std::{fmt, ops::Range};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "Error"),
+            Severity::Warning => write!(f, "Warning"),
+        }
+    }
+}
+
+/// What a diagnostic points at, which decides how its location is described.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Location {
+    /// A token, quoted by its lexeme.
+    Token,
+    /// The end of the source.
+    End,
+    /// Characters the scanner rejected. The message already says why.
+    Invalid,
+}
+
+/// A problem found while compiling, collected instead of printed so that
+/// callers can decide how to present it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    /// Byte range of the offending token in the source.
+    pub span: Range<usize>,
+    pub line: usize,
+    pub location: Location,
+}
+
+/// Formats a diagnostic the way the command line reports it, e.g.
+/// `[line 1] Error at ')': Expect expression.`.
+pub fn render(diagnostic: &Diagnostic, source: &str) -> String {
+    let location = match diagnostic.location {
+        Location::Token => format!(
+            " at '{}'",
+            source.get(diagnostic.span.clone()).unwrap_or_default()
+        ),
+        Location::End => " at end".to_string(),
+        Location::Invalid => String::new(),
+    };
+    format!(
+        "[line {}] {}{}: {}",
+        diagnostic.line, diagnostic.severity, location, diagnostic.message
+    )
+}
@@ -0,0 +1,270 @@
+//! Runs Lox scripts annotated in the style of the upstream test suite and
+//! checks what they print against their comments:
+//!
+//! - `// expect: value` for each line written by `print`,
+//! - `// Error at 'x': message` for a compile error on the same line, or
+//!   `// [line N] Error ...` for one reported elsewhere,
+//! - `// expect runtime error: message` for the error the script stops with.
+
+use std::{
+    cell::RefCell,
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+
+use crate::vm::{Config, InterpretError, VM};
+
+/// A writer whose contents can still be read after it has been handed to a
+/// `VM`.
+#[derive(Clone, Default)]
+pub(crate) struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+impl SharedBuffer {
+    pub(crate) fn contents(&self) -> String {
+        String::from_utf8_lossy(&self.0.borrow()).into_owned()
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct Expectations {
+    /// Each expected line of output, with the line of the script expecting it.
+    output: Vec<(usize, String)>,
+    /// Compile errors as they are reported, e.g. `[line 1] Error at ...`.
+    compile_errors: Vec<String>,
+    /// The runtime error message and the line it is raised on.
+    runtime_error: Option<(usize, String)>,
+}
+
+fn parse_expectations(source: &str) -> Expectations {
+    let mut expectations = Expectations::default();
+    for (index, text) in source.lines().enumerate() {
+        let line = index + 1;
+        // Markers are found anywhere on the line rather than at its first
+        // `// `, which may be inside a string literal.
+        let after = |marker: &str| text.find(marker).map(|start| &text[start + marker.len()..]);
+        if let Some(value) = after("// expect: ") {
+            expectations.output.push((line, value.to_string()));
+        } else if let Some(message) = after("// expect runtime error: ") {
+            expectations.runtime_error = Some((line, message.to_string()));
+        } else if let Some(error) = after("// [line ").or_else(|| after("// [c line ")) {
+            expectations.compile_errors.push(format!("[line {}", error));
+        } else if let Some(error) = after("// Error") {
+            expectations
+                .compile_errors
+                .push(format!("[line {}] Error{}", line, error));
+        }
+    }
+    expectations
+}
+
+/// The outcome of running one script.
+#[derive(Debug)]
+pub struct FileResult {
+    pub path: PathBuf,
+    /// Every way the script's behaviour differed from its annotations.
+    pub failures: Vec<String>,
+    /// The lines the script was expected to print, and the lines it did.
+    pub expected_output: Vec<String>,
+    pub output: Vec<String>,
+}
+
+impl FileResult {
+    pub fn passed(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+/// Runs the script at `path` in a fresh VM built from `config` and checks it
+/// against its annotations.
+pub fn run_file(path: &Path, config: &Config) -> io::Result<FileResult> {
+    let source = fs::read_to_string(path)?;
+    let expected = parse_expectations(&source);
+
+    let (out, err) = (SharedBuffer::default(), SharedBuffer::default());
+    let mut vm = VM::with_config(config.clone());
+    vm.set_output(Box::new(out.clone()));
+    vm.set_error_output(Box::new(err.clone()));
+    let result = vm.interpret(&source);
+    let (out, err) = (out.contents(), err.contents());
+    let errors: Vec<&str> = err.lines().collect();
+
+    let mut failures = Vec::new();
+    match result {
+        Err(InterpretError::INTERPRET_COMPILE_ERROR) => {
+            check_compile_errors(&expected.compile_errors, &errors, &mut failures)
+        }
+        Err(InterpretError::INTERPRET_RUNTIME_ERROR) => {
+            check_runtime_error(expected.runtime_error.as_ref(), &errors, &mut failures)
+        }
+        Ok(()) => {
+            for error in &expected.compile_errors {
+                failures.push(format!("Missing expected error: {}", error));
+            }
+            if let Some((line, message)) = &expected.runtime_error {
+                failures.push(format!(
+                    "Missing expected runtime error '{}' on line {}.",
+                    message, line
+                ));
+            }
+            for error in &errors {
+                failures.push(format!("Unexpected output on stderr: {}", error));
+            }
+        }
+    }
+    check_output(&expected.output, &out, &mut failures);
+
+    Ok(FileResult {
+        path: path.to_path_buf(),
+        failures,
+        expected_output: expected
+            .output
+            .into_iter()
+            .map(|(_, value)| value)
+            .collect(),
+        output: out.lines().map(str::to_string).collect(),
+    })
+}
+
+fn check_compile_errors(expected: &[String], errors: &[&str], failures: &mut Vec<String>) {
+    for error in errors {
+        if !expected.iter().any(|e| e == error) {
+            failures.push(format!("Unexpected error: {}", error));
+        }
+    }
+    for error in expected {
+        if !errors.contains(&error.as_str()) {
+            failures.push(format!("Missing expected error: {}", error));
+        }
+    }
+}
+
+fn check_runtime_error(
+    expected: Option<&(usize, String)>,
+    errors: &[&str],
+    failures: &mut Vec<String>,
+) {
+    let Some((line, message)) = expected else {
+        failures.push(format!("Unexpected runtime error: {}", errors.join("\n")));
+        return;
+    };
+    match errors.first() {
+        Some(&error) if error == message => {}
+        Some(&error) => failures.push(format!(
+            "Expected runtime error '{}' and got: {}",
+            message, error
+        )),
+        None => failures.push(format!(
+            "Expected runtime error '{}' and got none.",
+            message
+        )),
+    }
+    // The innermost frame of the stack trace is the line that failed.
+    let trace_line = format!("[line {}]", line);
+    match errors.get(1) {
+        Some(trace) if trace.starts_with(&trace_line) => {}
+        Some(&trace) => failures.push(format!(
+            "Expected stack trace starting with '{}' and got: {}",
+            trace_line, trace
+        )),
+        None => failures.push(format!(
+            "Expected stack trace starting with '{}' and got none.",
+            trace_line
+        )),
+    }
+}
+
+fn check_output(expected: &[(usize, String)], output: &str, failures: &mut Vec<String>) {
+    let actual: Vec<&str> = output.lines().collect();
+    for (index, (line, value)) in expected.iter().enumerate() {
+        match actual.get(index) {
+            Some(&got) if got == value => {}
+            Some(&got) => failures.push(format!(
+                "Expected output '{}' on line {} and got '{}'.",
+                value, line, got
+            )),
+            None => failures.push(format!(
+                "Missing expected output '{}' on line {}.",
+                value, line
+            )),
+        }
+    }
+    for got in actual.iter().skip(expected.len()) {
+        failures.push(format!("Got output '{}' when none was expected.", got));
+    }
+}
+
+/// Runs every `.lox` file under `dir`, in path order.
+pub fn run_dir(dir: &Path, config: &Config) -> io::Result<Vec<FileResult>> {
+    let mut paths = Vec::new();
+    collect_scripts(dir, &mut paths)?;
+    paths.sort();
+    paths.iter().map(|path| run_file(path, config)).collect()
+}
+
+fn collect_scripts(dir: &Path, paths: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_scripts(&path, paths)?;
+        } else if path.extension().is_some_and(|extension| extension == "lox") {
+            paths.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Writes a line per script, followed by its failures and a diff of its
+/// output, and a summary.
+pub fn report(out: &mut dyn Write, results: &[FileResult]) -> io::Result<()> {
+    for result in results {
+        if result.passed() {
+            writeln!(out, "PASS {}", result.path.display())?;
+            continue;
+        }
+        writeln!(out, "FAIL {}", result.path.display())?;
+        for failure in &result.failures {
+            writeln!(out, "     {}", failure)?;
+        }
+        if result.expected_output != result.output {
+            write_diff(out, &result.expected_output, &result.output)?;
+        }
+    }
+    let passed = results.iter().filter(|result| result.passed()).count();
+    writeln!(out, "{} passed, {} failed.", passed, results.len() - passed)
+}
+
+/// Writes expected and actual output side by side, line for line: matching
+/// lines once, and differing ones as `-` expected followed by `+` actual.
+fn write_diff(out: &mut dyn Write, expected: &[String], actual: &[String]) -> io::Result<()> {
+    writeln!(out, "     --- expected")?;
+    writeln!(out, "     +++ actual")?;
+    for index in 0..expected.len().max(actual.len()) {
+        match (expected.get(index), actual.get(index)) {
+            (Some(expected), Some(actual)) if expected == actual => {
+                writeln!(out, "       {}", expected)?
+            }
+            (expected, actual) => {
+                if let Some(expected) = expected {
+                    writeln!(out, "     - {}", expected)?;
+                }
+                if let Some(actual) = actual {
+                    writeln!(out, "     + {}", actual)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,523 @@
+//! The layout of bytecode instructions, described once so that the VM, the
+//! disassembler and other tools decode operands the same way.
+
+use crate::chunk::{Chunk, OpCode};
+
+/// The kinds of operand that follow an opcode.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OperandKind {
+    /// A one-byte stack slot, upvalue index or argument count.
+    Byte,
+    /// A two-byte jump distance.
+    Jump,
+    /// A one-byte index into the constant table.
+    Constant,
+    /// A three-byte index into the constant table.
+    LongConstant,
+    /// A two-byte count followed by that many `(is_local, index)` byte
+    /// pairs, one for each variable a closure captures. The count takes two
+    /// bytes because a function may capture 256 variables.
+    Upvalues,
+}
+
+impl OperandKind {
+    /// The number of bytes holding the operand's value, which for `Upvalues`
+    /// is the count.
+    pub fn value_width(self) -> usize {
+        match self {
+            OperandKind::Byte | OperandKind::Constant => 1,
+            OperandKind::Jump | OperandKind::Upvalues => 2,
+            OperandKind::LongConstant => 3,
+        }
+    }
+
+    /// The number of bytes taken up by the operand at the start of `code`,
+    /// including the pairs that follow an upvalue count.
+    pub fn width(self, code: &[u8]) -> usize {
+        match self {
+            OperandKind::Upvalues => 2 + 2 * u16::from_be_bytes([code[0], code[1]]) as usize,
+            kind => kind.value_width(),
+        }
+    }
+}
+
+/// What there is to know about an opcode without decoding its operands.
+#[derive(Debug)]
+pub struct OpInfo {
+    pub name: &'static str,
+    pub operands: &'static [OperandKind],
+    /// How many values executing the instruction adds to the stack, or
+    /// removes if negative. `None` when it depends on an operand; see
+    /// `Instruction::stack_effect`.
+    pub stack_effect: Option<isize>,
+}
+
+const fn info(
+    name: &'static str,
+    operands: &'static [OperandKind],
+    stack_effect: Option<isize>,
+) -> OpInfo {
+    OpInfo {
+        name,
+        operands,
+        stack_effect,
+    }
+}
+
+use OperandKind::{Byte, Constant, Jump, LongConstant, Upvalues};
+
+/// Indexed by opcode, so the order must match `OpCode`.
+static OP_INFO: [OpInfo; 49] = [
+    info("OP_RETURN", &[], Some(-1)),
+    info("OP_NEGATE", &[], Some(0)),
+    info("OP_ADD", &[], Some(-1)),
+    info("OP_SUBTRACT", &[], Some(-1)),
+    info("OP_MULTIPLY", &[], Some(-1)),
+    info("OP_DIVIDE", &[], Some(-1)),
+    info("OP_CONSTANT", &[Constant], Some(1)),
+    info("OP_NIL", &[], Some(1)),
+    info("OP_TRUE", &[], Some(1)),
+    info("OP_FALSE", &[], Some(1)),
+    info("OP_NOT", &[], Some(0)),
+    info("OP_EQUAL", &[], Some(-1)),
+    info("OP_GREATER", &[], Some(-1)),
+    info("OP_LESS", &[], Some(-1)),
+    info("OP_PRINT", &[], Some(-1)),
+    info("OP_POP", &[], Some(-1)),
+    info("OP_DEFINE_GLOBAL", &[Constant], Some(-1)),
+    info("OP_GET_GLOBAL", &[Constant], Some(1)),
+    info("OP_SET_GLOBAL", &[Constant], Some(0)),
+    info("OP_GET_LOCAL", &[Byte], Some(1)),
+    info("OP_SET_LOCAL", &[Byte], Some(0)),
+    info("OP_JUMP", &[Jump], Some(0)),
+    info("OP_JUMP_IF_FALSE", &[Jump], Some(0)),
+    info("OP_LOOP", &[Jump], Some(0)),
+    info("OP_CALL", &[Byte], None),
+    info("OP_CLOSURE", &[Constant, Upvalues], Some(1)),
+    info("OP_GET_UPVALUE", &[Byte], Some(1)),
+    info("OP_SET_UPVALUE", &[Byte], Some(0)),
+    info("OP_CLOSE_UPVALUE", &[], Some(-1)),
+    info("OP_CLASS", &[Constant], Some(1)),
+    info("OP_GET_PROPERTY", &[Constant], Some(0)),
+    info("OP_SET_PROPERTY", &[Constant], Some(-1)),
+    info("OP_METHOD", &[Constant], Some(-1)),
+    info("OP_INVOKE", &[Constant, Byte], None),
+    info("OP_INHERIT", &[], Some(-1)),
+    info("OP_GET_SUPER", &[Constant], Some(-1)),
+    info("OP_SUPER_INVOKE", &[Constant, Byte], None),
+    info("OP_CONSTANT_LONG", &[LongConstant], Some(1)),
+    info("OP_DEFINE_GLOBAL_LONG", &[LongConstant], Some(-1)),
+    info("OP_GET_GLOBAL_LONG", &[LongConstant], Some(1)),
+    info("OP_SET_GLOBAL_LONG", &[LongConstant], Some(0)),
+    info("OP_CLOSURE_LONG", &[LongConstant, Upvalues], Some(1)),
+    info("OP_CLASS_LONG", &[LongConstant], Some(1)),
+    info("OP_GET_PROPERTY_LONG", &[LongConstant], Some(0)),
+    info("OP_SET_PROPERTY_LONG", &[LongConstant], Some(-1)),
+    info("OP_METHOD_LONG", &[LongConstant], Some(-1)),
+    info("OP_INVOKE_LONG", &[LongConstant, Byte], None),
+    info("OP_GET_SUPER_LONG", &[LongConstant], Some(-1)),
+    info("OP_SUPER_INVOKE_LONG", &[LongConstant, Byte], None),
+];
+
+impl OpCode {
+    pub fn info(self) -> &'static OpInfo {
+        &OP_INFO[u8::from(self) as usize]
+    }
+}
+
+/// A variable captured by `OP_CLOSURE`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CapturedUpvalue {
+    /// Whether `index` is a local slot of the enclosing function, rather
+    /// than one of its upvalues.
+    pub is_local: bool,
+    pub index: u8,
+}
+
+/// Where the variables captured by a closure instruction are found in its
+/// chunk. They are read from the code on demand, so that decoding the
+/// instruction allocates nothing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CapturedUpvalues {
+    /// The offset of the first `(is_local, index)` pair.
+    pub offset: usize,
+    pub count: usize,
+}
+
+impl CapturedUpvalues {
+    pub fn get(&self, chunk: &Chunk, index: usize) -> CapturedUpvalue {
+        let pair = self.offset + 2 * index;
+        CapturedUpvalue {
+            is_local: chunk.codes[pair] == 1,
+            index: chunk.codes[pair + 1],
+        }
+    }
+
+    pub fn iter<'a>(&self, chunk: &'a Chunk) -> impl Iterator<Item = CapturedUpvalue> + 'a {
+        let upvalues = *self;
+        (0..self.count).map(move |index| upvalues.get(chunk, index))
+    }
+}
+
+/// A decoded instruction. Constant operands are indices into the chunk's
+/// constant table, and jumps are distances from the end of the instruction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Instruction {
+    Return,
+    Negate,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Constant(usize),
+    Nil,
+    True,
+    False,
+    Not,
+    Equal,
+    Greater,
+    Less,
+    Print,
+    Pop,
+    DefineGlobal(usize),
+    GetGlobal(usize),
+    SetGlobal(usize),
+    GetLocal(u8),
+    SetLocal(u8),
+    Jump(u16),
+    JumpIfFalse(u16),
+    Loop(u16),
+    Call(u8),
+    Closure {
+        function: usize,
+        upvalues: CapturedUpvalues,
+    },
+    GetUpvalue(u8),
+    SetUpvalue(u8),
+    CloseUpvalue,
+    Class(usize),
+    GetProperty(usize),
+    SetProperty(usize),
+    Method(usize),
+    Invoke {
+        name: usize,
+        arg_count: u8,
+    },
+    Inherit,
+    GetSuper(usize),
+    SuperInvoke {
+        name: usize,
+        arg_count: u8,
+    },
+    ConstantLong(usize),
+    DefineGlobalLong(usize),
+    GetGlobalLong(usize),
+    SetGlobalLong(usize),
+    ClosureLong {
+        function: usize,
+        upvalues: CapturedUpvalues,
+    },
+    ClassLong(usize),
+    GetPropertyLong(usize),
+    SetPropertyLong(usize),
+    MethodLong(usize),
+    InvokeLong {
+        name: usize,
+        arg_count: u8,
+    },
+    GetSuperLong(usize),
+    SuperInvokeLong {
+        name: usize,
+        arg_count: u8,
+    },
+    /// A byte that is not a valid opcode.
+    Unknown(u8),
+}
+
+impl Instruction {
+    pub fn opcode(&self) -> Option<OpCode> {
+        Some(match self {
+            Instruction::Return => OpCode::OP_RETURN,
+            Instruction::Negate => OpCode::OP_NEGATE,
+            Instruction::Add => OpCode::OP_ADD,
+            Instruction::Subtract => OpCode::OP_SUBTRACT,
+            Instruction::Multiply => OpCode::OP_MULTIPLY,
+            Instruction::Divide => OpCode::OP_DIVIDE,
+            Instruction::Constant(_) => OpCode::OP_CONSTANT,
+            Instruction::Nil => OpCode::OP_NIL,
+            Instruction::True => OpCode::OP_TRUE,
+            Instruction::False => OpCode::OP_FALSE,
+            Instruction::Not => OpCode::OP_NOT,
+            Instruction::Equal => OpCode::OP_EQUAL,
+            Instruction::Greater => OpCode::OP_GREATER,
+            Instruction::Less => OpCode::OP_LESS,
+            Instruction::Print => OpCode::OP_PRINT,
+            Instruction::Pop => OpCode::OP_POP,
+            Instruction::DefineGlobal(_) => OpCode::OP_DEFINE_GLOBAL,
+            Instruction::GetGlobal(_) => OpCode::OP_GET_GLOBAL,
+            Instruction::SetGlobal(_) => OpCode::OP_SET_GLOBAL,
+            Instruction::GetLocal(_) => OpCode::OP_GET_LOCAL,
+            Instruction::SetLocal(_) => OpCode::OP_SET_LOCAL,
+            Instruction::Jump(_) => OpCode::OP_JUMP,
+            Instruction::JumpIfFalse(_) => OpCode::OP_JUMP_IF_FALSE,
+            Instruction::Loop(_) => OpCode::OP_LOOP,
+            Instruction::Call(_) => OpCode::OP_CALL,
+            Instruction::Closure { .. } => OpCode::OP_CLOSURE,
+            Instruction::GetUpvalue(_) => OpCode::OP_GET_UPVALUE,
+            Instruction::SetUpvalue(_) => OpCode::OP_SET_UPVALUE,
+            Instruction::CloseUpvalue => OpCode::OP_CLOSE_UPVALUE,
+            Instruction::Class(_) => OpCode::OP_CLASS,
+            Instruction::GetProperty(_) => OpCode::OP_GET_PROPERTY,
+            Instruction::SetProperty(_) => OpCode::OP_SET_PROPERTY,
+            Instruction::Method(_) => OpCode::OP_METHOD,
+            Instruction::Invoke { .. } => OpCode::OP_INVOKE,
+            Instruction::Inherit => OpCode::OP_INHERIT,
+            Instruction::GetSuper(_) => OpCode::OP_GET_SUPER,
+            Instruction::SuperInvoke { .. } => OpCode::OP_SUPER_INVOKE,
+            Instruction::ConstantLong(_) => OpCode::OP_CONSTANT_LONG,
+            Instruction::DefineGlobalLong(_) => OpCode::OP_DEFINE_GLOBAL_LONG,
+            Instruction::GetGlobalLong(_) => OpCode::OP_GET_GLOBAL_LONG,
+            Instruction::SetGlobalLong(_) => OpCode::OP_SET_GLOBAL_LONG,
+            Instruction::ClosureLong { .. } => OpCode::OP_CLOSURE_LONG,
+            Instruction::ClassLong(_) => OpCode::OP_CLASS_LONG,
+            Instruction::GetPropertyLong(_) => OpCode::OP_GET_PROPERTY_LONG,
+            Instruction::SetPropertyLong(_) => OpCode::OP_SET_PROPERTY_LONG,
+            Instruction::MethodLong(_) => OpCode::OP_METHOD_LONG,
+            Instruction::InvokeLong { .. } => OpCode::OP_INVOKE_LONG,
+            Instruction::GetSuperLong(_) => OpCode::OP_GET_SUPER_LONG,
+            Instruction::SuperInvokeLong { .. } => OpCode::OP_SUPER_INVOKE_LONG,
+            Instruction::Unknown(_) => return None,
+        })
+    }
+
+    /// How many values executing the instruction adds to the stack, or
+    /// removes if negative.
+    pub fn stack_effect(&self) -> isize {
+        match self {
+            // The callee and arguments are replaced by the result.
+            Instruction::Call(arg_count)
+            | Instruction::Invoke { arg_count, .. }
+            | Instruction::InvokeLong { arg_count, .. } => -(*arg_count as isize),
+            // The superclass is popped as well.
+            Instruction::SuperInvoke { arg_count, .. }
+            | Instruction::SuperInvokeLong { arg_count, .. } => -(*arg_count as isize) - 1,
+            Instruction::Unknown(_) => 0,
+            instruction => instruction
+                .opcode()
+                .and_then(|op| op.info().stack_effect)
+                .unwrap_or(0),
+        }
+    }
+}
+
+/// Decodes the instruction at `offset`, returning it with the offset of the
+/// instruction after it.
+#[inline]
+pub(crate) fn decode(chunk: &Chunk, offset: usize) -> (Instruction, usize) {
+    let byte = chunk.codes[offset];
+    let Ok(op) = OpCode::try_from(byte) else {
+        return (Instruction::Unknown(byte), offset + 1);
+    };
+
+    let mut operands = [0; 2];
+    let mut next = offset + 1;
+    for (operand, kind) in operands.iter_mut().zip(op.info().operands) {
+        let bytes = &chunk.codes[next..next + kind.value_width()];
+        *operand = bytes
+            .iter()
+            .fold(0, |value, &byte| (value << 8) | byte as usize);
+        next += kind.width(&chunk.codes[next..]);
+    }
+    let [a, b] = operands;
+
+    let instruction = match op {
+        OpCode::OP_RETURN => Instruction::Return,
+        OpCode::OP_NEGATE => Instruction::Negate,
+        OpCode::OP_ADD => Instruction::Add,
+        OpCode::OP_SUBTRACT => Instruction::Subtract,
+        OpCode::OP_MULTIPLY => Instruction::Multiply,
+        OpCode::OP_DIVIDE => Instruction::Divide,
+        OpCode::OP_CONSTANT => Instruction::Constant(a),
+        OpCode::OP_NIL => Instruction::Nil,
+        OpCode::OP_TRUE => Instruction::True,
+        OpCode::OP_FALSE => Instruction::False,
+        OpCode::OP_NOT => Instruction::Not,
+        OpCode::OP_EQUAL => Instruction::Equal,
+        OpCode::OP_GREATER => Instruction::Greater,
+        OpCode::OP_LESS => Instruction::Less,
+        OpCode::OP_PRINT => Instruction::Print,
+        OpCode::OP_POP => Instruction::Pop,
+        OpCode::OP_DEFINE_GLOBAL => Instruction::DefineGlobal(a),
+        OpCode::OP_GET_GLOBAL => Instruction::GetGlobal(a),
+        OpCode::OP_SET_GLOBAL => Instruction::SetGlobal(a),
+        OpCode::OP_GET_LOCAL => Instruction::GetLocal(a as u8),
+        OpCode::OP_SET_LOCAL => Instruction::SetLocal(a as u8),
+        OpCode::OP_JUMP => Instruction::Jump(a as u16),
+        OpCode::OP_JUMP_IF_FALSE => Instruction::JumpIfFalse(a as u16),
+        OpCode::OP_LOOP => Instruction::Loop(a as u16),
+        OpCode::OP_CALL => Instruction::Call(a as u8),
+        OpCode::OP_CLOSURE | OpCode::OP_CLOSURE_LONG => {
+            let upvalues = CapturedUpvalues {
+                offset: next - 2 * b,
+                count: b,
+            };
+            if op == OpCode::OP_CLOSURE {
+                Instruction::Closure {
+                    function: a,
+                    upvalues,
+                }
+            } else {
+                Instruction::ClosureLong {
+                    function: a,
+                    upvalues,
+                }
+            }
+        }
+        OpCode::OP_GET_UPVALUE => Instruction::GetUpvalue(a as u8),
+        OpCode::OP_SET_UPVALUE => Instruction::SetUpvalue(a as u8),
+        OpCode::OP_CLOSE_UPVALUE => Instruction::CloseUpvalue,
+        OpCode::OP_CLASS => Instruction::Class(a),
+        OpCode::OP_GET_PROPERTY => Instruction::GetProperty(a),
+        OpCode::OP_SET_PROPERTY => Instruction::SetProperty(a),
+        OpCode::OP_METHOD => Instruction::Method(a),
+        OpCode::OP_INVOKE => Instruction::Invoke {
+            name: a,
+            arg_count: b as u8,
+        },
+        OpCode::OP_INHERIT => Instruction::Inherit,
+        OpCode::OP_GET_SUPER => Instruction::GetSuper(a),
+        OpCode::OP_SUPER_INVOKE => Instruction::SuperInvoke {
+            name: a,
+            arg_count: b as u8,
+        },
+        OpCode::OP_CONSTANT_LONG => Instruction::ConstantLong(a),
+        OpCode::OP_DEFINE_GLOBAL_LONG => Instruction::DefineGlobalLong(a),
+        OpCode::OP_GET_GLOBAL_LONG => Instruction::GetGlobalLong(a),
+        OpCode::OP_SET_GLOBAL_LONG => Instruction::SetGlobalLong(a),
+        OpCode::OP_CLASS_LONG => Instruction::ClassLong(a),
+        OpCode::OP_GET_PROPERTY_LONG => Instruction::GetPropertyLong(a),
+        OpCode::OP_SET_PROPERTY_LONG => Instruction::SetPropertyLong(a),
+        OpCode::OP_METHOD_LONG => Instruction::MethodLong(a),
+        OpCode::OP_INVOKE_LONG => Instruction::InvokeLong {
+            name: a,
+            arg_count: b as u8,
+        },
+        OpCode::OP_GET_SUPER_LONG => Instruction::GetSuperLong(a),
+        OpCode::OP_SUPER_INVOKE_LONG => Instruction::SuperInvokeLong {
+            name: a,
+            arg_count: b as u8,
+        },
+    };
+    (instruction, next)
+}
+
+/// Iterates over the instructions of a chunk. See `Chunk::instructions`.
+pub struct Instructions<'a> {
+    chunk: &'a Chunk,
+    offset: usize,
+}
+
+impl<'a> Instructions<'a> {
+    pub(crate) fn new(chunk: &'a Chunk) -> Self {
+        Instructions { chunk, offset: 0 }
+    }
+}
+
+impl Iterator for Instructions<'_> {
+    /// The offset and source line of each instruction, along with the
+    /// instruction itself.
+    type Item = (usize, usize, Instruction);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.offset >= self.chunk.codes.len() {
+            return None;
+        }
+        let offset = self.offset;
+        let (instruction, next) = decode(self.chunk, offset);
+        self.offset = next;
+        Some((offset, self.chunk.line_at(offset), instruction))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compile::compile, object::Object, value::Value, vm::VM};
+
+    #[test]
+    fn table_matches_opcodes() {
+        for byte in 0..OP_INFO.len() {
+            let op = OpCode::try_from(byte as u8).unwrap();
+            assert_eq!(op.info().name, format!("{:?}", op));
+        }
+        assert!(OpCode::try_from(OP_INFO.len() as u8).is_err());
+    }
+
+    #[test]
+    fn instructions_cover_the_chunk() {
+        let source =
+            "var a = 1;\nfun f(b) { fun g() { return a + b; } return g; }\nprint f(2)();\n";
+        let mut vm = VM::new();
+        let function = compile(source, &mut vm).unwrap();
+        let chunk = &vm.heap.as_function(function).chunk;
+
+        let instructions: Vec<_> = chunk.instructions().collect();
+        let mut next = 0;
+        for (offset, _, instruction) in &instructions {
+            assert_eq!(*offset, next);
+            next = chunk.instruction_at(*offset).1;
+            assert!(!matches!(instruction, Instruction::Unknown(_)));
+        }
+        assert_eq!(next, chunk.codes.len());
+
+        let (_, line, closure) = &instructions[2];
+        assert_eq!(*line, 2);
+        assert!(matches!(closure, Instruction::Closure { upvalues, .. } if upvalues.count == 0));
+        assert_eq!(instructions.last().unwrap().2, Instruction::Return);
+    }
+
+    #[test]
+    fn straight_line_code_leaves_the_stack_balanced() {
+        let source = "var a = 1;\nprint -a + 2 * a;\nvar b = a;\nb = !nil == true;\n";
+        let mut vm = VM::new();
+        let function = compile(source, &mut vm).unwrap();
+        let chunk = &vm.heap.as_function(function).chunk;
+
+        let effect: isize = chunk
+            .instructions()
+            .map(|(_, _, instruction)| instruction.stack_effect())
+            .sum();
+        assert_eq!(effect, 0);
+    }
+
+    #[test]
+    fn table_widths_step_over_every_instruction() {
+        let source = "var a = 1;\nfun f(b) {\n  var c = 2;\n  fun g() { return a + b + c; }\n  return g;\n}\nprint f(2)();\n";
+        let mut vm = VM::new();
+        let function = compile(source, &mut vm).unwrap();
+        let chunk = &vm.heap.as_function(function).chunk;
+        // Walk f, which holds a closure that captures variables.
+        let f = chunk
+            .values
+            .iter()
+            .find_map(|value| match value {
+                Value::Obj(obj) if matches!(vm.heap.get(*obj), Object::Function(_)) => Some(*obj),
+                _ => None,
+            })
+            .unwrap();
+        let chunk = &vm.heap.as_function(f).chunk;
+
+        let mut offset = 0;
+        let mut offsets = Vec::new();
+        while offset < chunk.codes.len() {
+            offsets.push(offset);
+            let op = OpCode::try_from(chunk.codes[offset]).unwrap();
+            offset += 1;
+            for kind in op.info().operands {
+                offset += kind.width(&chunk.codes[offset..]);
+            }
+        }
+        let decoded: Vec<usize> = chunk.instructions().map(|(offset, _, _)| offset).collect();
+        assert_eq!(offsets, decoded);
+    }
+}
@@ -1,47 +1,63 @@
 use std::{
-    fs::File,
-    io::{self, BufRead, BufReader, Read},
+    fs,
+    io::{self, Read},
+    process::ExitCode,
 };
 
-use vm::VM;
+use vm::{InterpretError, VM};
 
 pub mod chunk;
 pub mod compile;
 pub mod debug;
+pub mod diagnostic;
+pub mod harness;
+pub mod instruction;
+pub mod memory;
+pub mod object;
+pub mod repl;
 pub mod scanner;
+pub mod trace;
 pub mod value;
 pub mod vm;
 
-pub fn repl(mut vm: VM) {
-    let mut buffer = String::with_capacity(1024);
-    let stdin = io::stdin();
-    loop {
-        print!("> ");
-        let handle = stdin.lock();
-        buffer.clear();
-        match handle.take(1024).read_line(&mut buffer) {
-            Ok(0) | Err(_) => {
-                println!();
-                break;
-            }
-            _ => {}
-        };
-        vm.interpret(&buffer);
+/// Exit statuses from BSD's sysexits.h.
+pub const EXIT_USAGE: u8 = 64;
+pub const EXIT_COMPILE_ERROR: u8 = 65;
+pub const EXIT_RUNTIME_ERROR: u8 = 70;
+pub const EXIT_IO_ERROR: u8 = 74;
+
+/// Interprets `source` and maps the outcome to the process exit status.
+pub fn run_source(vm: &mut VM, source: &str) -> ExitCode {
+    match vm.interpret(source) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(InterpretError::INTERPRET_COMPILE_ERROR) => ExitCode::from(EXIT_COMPILE_ERROR),
+        Err(InterpretError::INTERPRET_RUNTIME_ERROR) => ExitCode::from(EXIT_RUNTIME_ERROR),
+    }
+}
+
+pub fn run_file(mut vm: VM, path: &str) -> ExitCode {
+    match fs::read_to_string(path) {
+        Ok(source) => run_source(&mut vm, &source),
+        Err(error) => {
+            eprintln!("Could not read file \"{}\": {}.", path, error);
+            ExitCode::from(EXIT_IO_ERROR)
+        }
     }
 }
 
-pub fn run_file(mut vm: VM, path: &str) {
-    let file = File::open(path).unwrap();
-    let mut buf_reader = BufReader::new(file);
-    let mut contents = String::new();
-    buf_reader.read_to_string(&mut contents).unwrap();
-    vm.interpret(&contents);
+pub fn run_stdin(mut vm: VM) -> ExitCode {
+    let mut source = String::new();
+    match io::stdin().read_to_string(&mut source) {
+        Ok(_) => run_source(&mut vm, &source),
+        Err(error) => {
+            eprintln!("Could not read from stdin: {}.", error);
+            ExitCode::from(EXIT_IO_ERROR)
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::*;
-
     #[test]
     fn it_works() {
         assert_eq!(4, 4);
@@ -1,30 +1,71 @@
+use std::{env, io, path::Path, process::ExitCode};
+
 use rlox::{
-    chunk::{Chunk, OpCode},
-    debug::disassemble_chunk,
-    repl,
-    vm::VM,
+    harness, repl, run_file, run_source, run_stdin,
+    trace::DisassemblyTracer,
+    vm::{Config, VM},
+    EXIT_IO_ERROR, EXIT_USAGE,
 };
 
-fn main() {
-    let mut x = Chunk::default();
-    // let constant_ref = x.add_constant(1.2);
-    // x.write_chunk(OpCode::OP_CONSTANT.into(), 123);
-    // x.write_chunk(constant_ref as u8, 123);
-
-    // let constant_ref = x.add_constant(3.4);
-    // x.write_chunk(OpCode::OP_CONSTANT.into(), 123);
-    // x.write_chunk(constant_ref as u8, 123);
+const USAGE: &str = "Usage: rlox [--trace] [script [args...] | -e code | -]
+       rlox test <dir>";
 
-    // x.write_chunk(OpCode::OP_ADD.into(), 123);
+fn main() -> ExitCode {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    if args.first().is_some_and(|arg| arg == "test") {
+        return match args.as_slice() {
+            [_, dir] => run_tests(Path::new(dir)),
+            _ => usage(),
+        };
+    }
 
-    // let constant_ref = x.add_constant(5.6);
-    // x.write_chunk(OpCode::OP_CONSTANT.into(), 123);
-    // x.write_chunk(constant_ref as u8, 123);
+    let mut vm = VM::new();
+    if args.first().is_some_and(|arg| arg == "--trace") {
+        args.remove(0);
+        vm.set_tracer(Box::new(DisassemblyTracer::default()));
+    }
+    match args.first().map(String::as_str) {
+        None => {
+            repl::run(vm);
+            ExitCode::SUCCESS
+        }
+        Some("-e") => match args.get(1) {
+            Some(code) => {
+                vm.set_args(args[2..].to_vec());
+                run_source(&mut vm, code)
+            }
+            None => usage(),
+        },
+        Some("-") => {
+            vm.set_args(args[1..].to_vec());
+            run_stdin(vm)
+        }
+        Some(flag) if flag.starts_with('-') => usage(),
+        Some(path) => {
+            let path = path.to_string();
+            vm.set_args(args[1..].to_vec());
+            run_file(vm, &path)
+        }
+    }
+}
 
-    // x.write_chunk(OpCode::OP_DIVIDE.into(), 123);
+fn usage() -> ExitCode {
+    eprintln!("{}", USAGE);
+    ExitCode::from(EXIT_USAGE)
+}
 
-    // x.write_chunk(OpCode::OP_NEGATE.into(), 123);
-    // x.write_chunk(OpCode::OP_RETURN.into(), 123);
-    // disassemble_chunk(&x, "Cool chunk");
-    VM::new(x).interpret(&"1+2".into());
+fn run_tests(dir: &Path) -> ExitCode {
+    let results = match harness::run_dir(dir, &Config::default()) {
+        Ok(results) => results,
+        Err(error) => {
+            eprintln!("Could not read '{}': {}.", dir.display(), error);
+            return ExitCode::from(EXIT_IO_ERROR);
+        }
+    };
+    let _ = harness::report(&mut io::stdout(), &results);
+    if results.iter().all(|result| result.passed()) {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
 }
@@ -0,0 +1,323 @@
+use std::{collections::HashMap, mem::size_of};
+
+use crate::{
+    object::{
+        ObjClass, ObjClosure, ObjFunction, ObjInstance, ObjRef, ObjString, ObjUpvalue, Object,
+    },
+    value::Value,
+};
+
+const INITIAL_GC_THRESHOLD: usize = 1024 * 1024;
+const GC_HEAP_GROW_FACTOR: usize = 2;
+
+/// Owns every object created by the compiler and the VM. Objects are freed by
+/// a mark-and-sweep collection: whoever allocates is responsible for marking
+/// its roots before calling `collect_garbage`.
+#[derive(Debug)]
+pub struct Heap {
+    /// `None` marks a slot freed by the collector, available for reuse.
+    objects: Vec<Option<Object>>,
+    marks: Vec<bool>,
+    /// The size charged to `bytes_allocated` for each object, so that freeing
+    /// it gives back exactly what was charged.
+    sizes: Vec<usize>,
+    free_slots: Vec<usize>,
+    gray: Vec<ObjRef>,
+    strings: HashMap<String, ObjRef>,
+    bytes_allocated: usize,
+    next_gc: usize,
+    /// Collect before every allocation, to shake out missing roots.
+    pub stress: bool,
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Heap {
+            objects: Vec::new(),
+            marks: Vec::new(),
+            sizes: Vec::new(),
+            free_slots: Vec::new(),
+            gray: Vec::new(),
+            strings: HashMap::new(),
+            bytes_allocated: 0,
+            next_gc: INITIAL_GC_THRESHOLD,
+            stress: false,
+        }
+    }
+}
+
+impl Heap {
+    /// Moves an object onto the heap. This never collects: callers should
+    /// check `should_collect` first, while everything they hold is rooted.
+    pub fn alloc(&mut self, object: Object) -> ObjRef {
+        let size = size_of_object(&object);
+        self.bytes_allocated += size;
+        match self.free_slots.pop() {
+            Some(index) => {
+                self.objects[index] = Some(object);
+                self.marks[index] = false;
+                self.sizes[index] = size;
+                ObjRef(index)
+            }
+            None => {
+                self.objects.push(Some(object));
+                self.marks.push(false);
+                self.sizes.push(size);
+                ObjRef(self.objects.len() - 1)
+            }
+        }
+    }
+
+    /// Charges an object that has grown since it was allocated, such as an
+    /// instance given new fields, for its current size.
+    pub fn resize(&mut self, obj: ObjRef) {
+        let size = size_of_object(self.get(obj));
+        self.bytes_allocated = self.bytes_allocated - self.sizes[obj.0] + size;
+        self.sizes[obj.0] = size;
+    }
+
+    pub fn get(&self, obj: ObjRef) -> &Object {
+        self.objects[obj.0].as_ref().expect("use of freed object")
+    }
+
+    pub fn get_mut(&mut self, obj: ObjRef) -> &mut Object {
+        self.objects[obj.0].as_mut().expect("use of freed object")
+    }
+
+    pub fn bytes_allocated(&self) -> usize {
+        self.bytes_allocated
+    }
+
+    pub fn should_collect(&self) -> bool {
+        self.stress || self.bytes_allocated > self.next_gc
+    }
+
+    pub fn mark_value(&mut self, value: Value) {
+        if let Value::Obj(obj) = value {
+            self.mark_object(obj);
+        }
+    }
+
+    pub fn mark_object(&mut self, obj: ObjRef) {
+        mark(&mut self.marks, &mut self.gray, obj);
+    }
+
+    /// Traces everything reachable from the marked roots, then frees the
+    /// rest. The threshold for the next collection grows with the live heap.
+    pub fn collect_garbage(&mut self) {
+        while let Some(obj) = self.gray.pop() {
+            self.blacken(obj);
+        }
+
+        // The intern table holds its strings weakly.
+        let marks = &self.marks;
+        self.strings.retain(|_, obj| marks[obj.0]);
+
+        for index in 0..self.objects.len() {
+            if self.marks[index] {
+                self.marks[index] = false;
+            } else if self.objects[index].take().is_some() {
+                self.bytes_allocated -= self.sizes[index];
+                self.free_slots.push(index);
+            }
+        }
+
+        self.next_gc = (self.bytes_allocated * GC_HEAP_GROW_FACTOR).max(INITIAL_GC_THRESHOLD);
+    }
+
+    fn blacken(&mut self, obj: ObjRef) {
+        let Heap {
+            objects,
+            marks,
+            gray,
+            ..
+        } = self;
+        let mut mark_value = |value: &Value| {
+            if let Value::Obj(obj) = value {
+                mark(marks, gray, *obj);
+            }
+        };
+        match objects[obj.0].as_ref().unwrap() {
+            Object::String(_) => {}
+            Object::Function(function) => {
+                if let Some(name) = function.name {
+                    mark_value(&Value::Obj(name));
+                }
+                function.chunk.values.iter().for_each(mark_value);
+            }
+            Object::Native(native) => mark_value(&Value::Obj(native.name)),
+            Object::Closure(closure) => {
+                mark_value(&Value::Obj(closure.function));
+                for &upvalue in &closure.upvalues {
+                    mark_value(&Value::Obj(upvalue));
+                }
+            }
+            Object::Upvalue(ObjUpvalue::Closed(value)) => mark_value(value),
+            Object::Upvalue(ObjUpvalue::Open(_)) => {}
+            Object::Class(class) => {
+                mark_value(&Value::Obj(class.name));
+                for (&name, method) in &class.methods {
+                    mark_value(&Value::Obj(name));
+                    mark_value(method);
+                }
+            }
+            Object::BoundMethod(bound) => {
+                mark_value(&bound.receiver);
+                mark_value(&Value::Obj(bound.method));
+            }
+            Object::Instance(instance) => {
+                mark_value(&Value::Obj(instance.class));
+                for (&name, value) in &instance.fields {
+                    mark_value(&Value::Obj(name));
+                    mark_value(value);
+                }
+            }
+        }
+    }
+
+    /// Returns the interned string with these contents, without creating one.
+    pub fn find_string(&self, chars: &str) -> Option<ObjRef> {
+        self.strings.get(chars).copied()
+    }
+
+    /// Returns the interned string with the given contents, allocating it if
+    /// it has not been seen before.
+    pub fn copy_string(&mut self, chars: &str) -> ObjRef {
+        match self.strings.get(chars) {
+            Some(&obj) => obj,
+            None => self.allocate_string(chars.to_string()),
+        }
+    }
+
+    /// Like `copy_string`, but takes ownership of an already built string.
+    pub fn take_string(&mut self, chars: String) -> ObjRef {
+        match self.strings.get(&chars) {
+            Some(&obj) => obj,
+            None => self.allocate_string(chars),
+        }
+    }
+
+    fn allocate_string(&mut self, chars: String) -> ObjRef {
+        let obj = self.alloc(Object::String(ObjString {
+            chars: chars.clone(),
+        }));
+        self.strings.insert(chars, obj);
+        obj
+    }
+
+    pub fn as_string(&self, obj: ObjRef) -> Option<&str> {
+        match self.get(obj) {
+            Object::String(s) => Some(&s.chars),
+            _ => None,
+        }
+    }
+
+    pub fn as_function(&self, obj: ObjRef) -> &ObjFunction {
+        match self.get(obj) {
+            Object::Function(f) => f,
+            _ => unreachable!("object is not a function"),
+        }
+    }
+
+    pub fn as_closure(&self, obj: ObjRef) -> &ObjClosure {
+        match self.get(obj) {
+            Object::Closure(c) => c,
+            _ => unreachable!("object is not a closure"),
+        }
+    }
+
+    pub fn as_class(&self, obj: ObjRef) -> &ObjClass {
+        match self.get(obj) {
+            Object::Class(c) => c,
+            _ => unreachable!("object is not a class"),
+        }
+    }
+
+    pub fn as_class_mut(&mut self, obj: ObjRef) -> &mut ObjClass {
+        match self.get_mut(obj) {
+            Object::Class(c) => c,
+            _ => unreachable!("object is not a class"),
+        }
+    }
+
+    pub fn as_instance(&self, obj: ObjRef) -> Option<&ObjInstance> {
+        match self.get(obj) {
+            Object::Instance(i) => Some(i),
+            _ => None,
+        }
+    }
+
+    pub fn as_instance_mut(&mut self, obj: ObjRef) -> Option<&mut ObjInstance> {
+        match self.get_mut(obj) {
+            Object::Instance(i) => Some(i),
+            _ => None,
+        }
+    }
+
+    pub fn as_upvalue(&self, obj: ObjRef) -> &ObjUpvalue {
+        match self.get(obj) {
+            Object::Upvalue(u) => u,
+            _ => unreachable!("object is not an upvalue"),
+        }
+    }
+
+    pub fn as_upvalue_mut(&mut self, obj: ObjRef) -> &mut ObjUpvalue {
+        match self.get_mut(obj) {
+            Object::Upvalue(u) => u,
+            _ => unreachable!("object is not an upvalue"),
+        }
+    }
+}
+
+fn mark(marks: &mut [bool], gray: &mut Vec<ObjRef>, obj: ObjRef) {
+    if !marks[obj.0] {
+        marks[obj.0] = true;
+        gray.push(obj);
+    }
+}
+
+/// An estimate of the memory an object owns, used to decide when to collect.
+fn size_of_object(object: &Object) -> usize {
+    size_of::<Object>()
+        + match object {
+            Object::String(s) => s.chars.capacity(),
+            Object::Function(f) => {
+                f.chunk.codes.capacity()
+                    + f.chunk.values.capacity() * size_of::<Value>()
+                    + f.chunk.line_table_size()
+            }
+            Object::Closure(c) => c.upvalues.capacity() * size_of::<ObjRef>(),
+            Object::Class(c) => c.methods.capacity() * size_of::<(ObjRef, Value)>(),
+            Object::Instance(i) => i.fields.capacity() * size_of::<(ObjRef, Value)>(),
+            Object::Native(_) | Object::Upvalue(_) | Object::BoundMethod(_) => 0,
+        }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn growth_is_charged_and_given_back_when_freed() {
+        let mut heap = Heap::default();
+        let class = heap.copy_string("Wide");
+        let instance = heap.alloc(Object::Instance(ObjInstance {
+            class,
+            fields: HashMap::new(),
+        }));
+        let empty = heap.bytes_allocated();
+
+        for i in 0..200 {
+            let name = heap.copy_string(&format!("f{i}"));
+            let fields = &mut heap.as_instance_mut(instance).unwrap().fields;
+            fields.insert(name, Value::Number(i as f64));
+            heap.resize(instance);
+        }
+        let fields = heap.as_instance(instance).unwrap().fields.capacity();
+        assert!(heap.bytes_allocated() >= empty + fields * size_of::<(ObjRef, Value)>());
+
+        // Nothing is marked, so everything is freed.
+        heap.collect_garbage();
+        assert_eq!(heap.bytes_allocated(), 0);
+    }
+}
@@ -0,0 +1,106 @@
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+};
+
+use crate::{chunk::Chunk, memory::Heap, value::Value, vm::VM};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ObjRef(pub(crate) usize);
+
+#[derive(Debug)]
+pub enum Object {
+    String(ObjString),
+    Function(ObjFunction),
+    Native(ObjNative),
+    Closure(ObjClosure),
+    Upvalue(ObjUpvalue),
+    Class(ObjClass),
+    Instance(ObjInstance),
+    BoundMethod(ObjBoundMethod),
+}
+
+#[derive(Debug)]
+pub struct ObjString {
+    pub chars: String,
+}
+
+#[derive(Debug)]
+pub struct ObjFunction {
+    pub arity: usize,
+    pub chunk: Chunk,
+    /// `None` for the top-level script.
+    pub name: Option<ObjRef>,
+}
+
+#[derive(Debug)]
+pub struct ObjClosure {
+    pub function: ObjRef,
+    pub upvalues: Vec<ObjRef>,
+}
+
+/// A variable captured by a closure. It points at the variable's stack slot
+/// until that slot goes out of scope, then holds the value itself.
+#[derive(Debug)]
+pub enum ObjUpvalue {
+    Open(usize),
+    Closed(Value),
+}
+
+#[derive(Debug)]
+pub struct ObjClass {
+    pub name: ObjRef,
+    /// Method names to their closures.
+    pub methods: HashMap<ObjRef, Value>,
+}
+
+#[derive(Debug)]
+pub struct ObjInstance {
+    pub class: ObjRef,
+    pub fields: HashMap<ObjRef, Value>,
+}
+
+/// A method read off an instance, remembering the instance it was read from.
+#[derive(Debug)]
+pub struct ObjBoundMethod {
+    pub receiver: Value,
+    pub method: ObjRef,
+}
+
+/// A function implemented by the host. Returning `Err` raises a Lox runtime
+/// error with the given message.
+pub type NativeFn = fn(&mut VM, &[Value]) -> Result<Value, String>;
+
+#[derive(Debug)]
+pub struct ObjNative {
+    pub name: ObjRef,
+    pub arity: usize,
+    pub function: NativeFn,
+}
+
+fn print_function(out: &mut dyn Write, function: &ObjFunction, heap: &Heap) -> io::Result<()> {
+    match function.name {
+        Some(name) => write!(out, "<fn {}>", heap.as_string(name).unwrap()),
+        None => write!(out, "<script>"),
+    }
+}
+
+pub fn print_object(out: &mut dyn Write, obj: ObjRef, heap: &Heap) -> io::Result<()> {
+    match heap.get(obj) {
+        Object::String(s) => write!(out, "{}", s.chars),
+        Object::Function(f) => print_function(out, f, heap),
+        Object::Native(_) => write!(out, "<native fn>"),
+        Object::Closure(c) => print_function(out, heap.as_function(c.function), heap),
+        Object::Upvalue(_) => write!(out, "upvalue"),
+        Object::BoundMethod(b) => {
+            let function = heap.as_closure(b.method).function;
+            print_function(out, heap.as_function(function), heap)
+        }
+        Object::Class(c) => write!(out, "{}", heap.as_string(c.name).unwrap()),
+        Object::Instance(i) => write!(
+            out,
+            "{} instance",
+            heap.as_string(heap.as_class(i.class).name).unwrap()
+        ),
+    }
+}
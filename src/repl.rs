@@ -0,0 +1,214 @@
+use std::{
+    fs,
+    io::{self, BufRead, Write},
+};
+
+use crate::{
+    debug::disassemble_chunk,
+    object::Object,
+    scanner::{Scanner, TokenType},
+    value::{print_value, Value},
+    vm::VM,
+};
+
+const HELP: &str = "\
+:dis <name>   disassemble the global function or closure <name>
+:globals      list every global variable and its value
+:load <file>  run a script in this session
+:reset        forget every global variable
+:help         show this message
+:quit         leave the REPL";
+
+/// Reads and runs input until end of file. Definitions persist between
+/// entries, input with unbalanced brackets continues on the next line, and a
+/// bare expression's value is printed.
+pub fn run(mut vm: VM) {
+    let stdin = io::stdin();
+    let mut input = stdin.lock();
+    let mut buffer = String::new();
+    loop {
+        print!("{}", if buffer.is_empty() { "> " } else { "... " });
+        let _ = io::stdout().flush();
+        let mut line = String::new();
+        match input.read_line(&mut line) {
+            Ok(0) | Err(_) => {
+                println!();
+                if !buffer.is_empty() {
+                    let _ = vm.interpret_repl(&buffer);
+                }
+                break;
+            }
+            Ok(_) => {}
+        }
+
+        if buffer.is_empty() {
+            if let Some(command) = line.trim().strip_prefix(':') {
+                if !run_command(&mut vm, command) {
+                    break;
+                }
+                continue;
+            }
+        }
+        buffer.push_str(&line);
+        if !is_incomplete(&buffer) {
+            let _ = vm.interpret_repl(&buffer);
+            buffer.clear();
+        }
+    }
+}
+
+/// Whether `source` ends inside a string or with brackets left open.
+fn is_incomplete(source: &str) -> bool {
+    let mut scanner = Scanner::new();
+    let mut depth: isize = 0;
+    loop {
+        match scanner.scan_token(source).token_type {
+            TokenType::LEFT_PAREN | TokenType::LEFT_BRACE => depth += 1,
+            TokenType::RIGHT_PAREN | TokenType::RIGHT_BRACE => depth -= 1,
+            TokenType::ERROR(message) if message == "Unterminated string." => return true,
+            TokenType::EOF => return depth > 0,
+            _ => {}
+        }
+    }
+}
+
+/// Runs a meta-command, returning false if the REPL should stop. Output and
+/// errors go to the VM's sinks, like the output of the code it runs.
+fn run_command(vm: &mut VM, command: &str) -> bool {
+    let (name, argument) = match command.split_once(char::is_whitespace) {
+        Some((name, argument)) => (name, argument.trim()),
+        None => (command, ""),
+    };
+    match (name, argument) {
+        ("dis", name) if !name.is_empty() => disassemble(vm, name),
+        ("globals", "") => {
+            let globals: Vec<(String, Value)> = vm
+                .globals()
+                .into_iter()
+                .map(|(name, value)| (name.to_string(), value))
+                .collect();
+            for (name, value) in globals {
+                let _ = write!(vm.out, "{} = ", name);
+                let _ = print_value(&mut vm.out, &value, &vm.heap);
+                let _ = writeln!(vm.out);
+            }
+        }
+        ("load", path) if !path.is_empty() => match fs::read_to_string(path) {
+            Ok(source) => {
+                let _ = vm.interpret(&source);
+            }
+            Err(error) => {
+                let _ = writeln!(vm.err, "Could not read file \"{}\": {}.", path, error);
+            }
+        },
+        ("reset", "") => vm.reset(),
+        ("help", "") => {
+            let _ = writeln!(vm.out, "{}", HELP);
+        }
+        ("quit", "") => return false,
+        _ => {
+            let _ = writeln!(vm.err, "Unknown command ':{}'. Try ':help'.", command);
+        }
+    }
+    true
+}
+
+fn disassemble(vm: &mut VM, name: &str) {
+    let function = match vm.global(name) {
+        Some(Value::Obj(obj)) => match vm.heap.get(obj) {
+            Object::Closure(closure) => vm.heap.as_function(closure.function),
+            Object::Function(function) => function,
+            _ => {
+                let _ = writeln!(vm.err, "'{}' is not a function.", name);
+                return;
+            }
+        },
+        Some(_) => {
+            let _ = writeln!(vm.err, "'{}' is not a function.", name);
+            return;
+        }
+        None => {
+            let _ = writeln!(vm.err, "Undefined variable '{}'.", name);
+            return;
+        }
+    };
+    let _ = disassemble_chunk(&mut vm.out, &function.chunk, name, &vm.heap);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::SharedBuffer;
+
+    #[test]
+    fn unbalanced_input_is_incomplete() {
+        assert!(is_incomplete("print (1 +"));
+        assert!(is_incomplete("fun f() {\n  print 1;"));
+        assert!(is_incomplete("print \"unterminated"));
+        assert!(!is_incomplete("print 1;"));
+        assert!(!is_incomplete("}"));
+        assert!(!is_incomplete("{ print 1; }}"));
+    }
+
+    #[test]
+    fn entries_share_globals_and_print_bare_expressions() {
+        let out = SharedBuffer::default();
+        let mut vm = VM::new();
+        vm.set_output(Box::new(out.clone()));
+
+        vm.interpret_repl("var a = 1;\nfun twice(x) { return x * 2; }")
+            .unwrap();
+        vm.interpret_repl("twice(a) + 1").unwrap();
+        assert_eq!(out.contents(), "3\n");
+    }
+
+    #[test]
+    fn reset_forgets_globals_and_quit_stops() {
+        let mut vm = VM::new();
+        vm.interpret_repl("var a = 1;").unwrap();
+        assert!(run_command(&mut vm, "reset"));
+        assert!(vm.global("a").is_none());
+        assert!(!run_command(&mut vm, "quit"));
+    }
+
+    #[test]
+    fn meta_commands_write_to_the_vm_sinks() {
+        let (out, err) = (SharedBuffer::default(), SharedBuffer::default());
+        let mut vm = VM::new();
+        vm.set_output(Box::new(out.clone()));
+        vm.set_error_output(Box::new(err.clone()));
+        vm.interpret_repl("var a = 1;\nfun f(x) { return a + x; }")
+            .unwrap();
+
+        assert!(run_command(&mut vm, "globals"));
+        assert_eq!(
+            out.contents(),
+            "a = 1\n\
+             arg = <native fn>\n\
+             argCount = <native fn>\n\
+             clock = <native fn>\n\
+             f = <fn f>\n"
+        );
+
+        let out = SharedBuffer::default();
+        vm.set_output(Box::new(out.clone()));
+        assert!(run_command(&mut vm, "dis f"));
+        assert_eq!(
+            out.contents(),
+            "== f ==\n\
+             0000    2 OP_GET_GLOBAL       0 'a'\n\
+             0002    | OP_GET_LOCAL        1\n\
+             0004    | OP_ADD\n\
+             0005    | OP_RETURN\n\
+             0006    | OP_NIL\n\
+             0007    | OP_RETURN\n"
+        );
+
+        assert!(run_command(&mut vm, "dis a"));
+        assert!(run_command(&mut vm, "bogus"));
+        assert_eq!(
+            err.contents(),
+            "'a' is not a function.\nUnknown command ':bogus'. Try ':help'.\n"
+        );
+    }
+}
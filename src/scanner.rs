@@ -1,3 +1,4 @@
+#![allow(non_camel_case_types)]
 #[derive(Debug)]
 pub struct Scanner {
     pub start: usize,
@@ -6,7 +7,17 @@ pub struct Scanner {
 }
 
 fn is_alpha(c: char) -> bool {
-    (c >= 'a' && c <= 'z') || (c >= 'A' && c <= 'Z') || (c == '_')
+    c.is_ascii_alphabetic() || c == '_'
+}
+
+fn is_digit(s: &str) -> bool {
+    s.chars().all(|c| c.is_ascii_digit())
+}
+
+impl Default for Scanner {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Scanner {
@@ -18,7 +29,7 @@ impl Scanner {
         }
     }
 
-    fn skip_whitespace(&mut self, source: &String) {
+    fn skip_whitespace(&mut self, source: &str) {
         loop {
             if let Some(s) = self.peek(source, 0) {
                 match s {
@@ -34,7 +45,7 @@ impl Scanner {
                             loop {
                                 if self.peek(source, 0) == Some("\n") || self.is_at_end(source) {
                                     break;
-                                };
+                                }
                                 self.advance(source);
                             }
                         } else {
@@ -49,23 +60,22 @@ impl Scanner {
         }
     }
 
-    fn peek<'a>(&'a self, source: &'a String, distance: usize) -> Option<&'a str> {
+    /// The character `distance` bytes ahead, which callers only look past
+    /// when the characters before it are ASCII.
+    fn peek<'a>(&'a self, source: &'a str, distance: usize) -> Option<&'a str> {
         let index = self.current + distance;
-        if index >= source.len() {
-            None
-        } else {
-            source.get(index..=index)
-        }
+        let c = source.get(index..)?.chars().next()?;
+        source.get(index..index + c.len_utf8())
     }
 
-    pub fn scan_token(&mut self, source: &String) -> Token {
+    pub fn scan_token(&mut self, source: &str) -> Token {
         self.skip_whitespace(source);
         self.start = self.current;
         if self.is_at_end(source) {
             self.make_token(TokenType::EOF)
         } else {
             let c = self.advance(source);
-            if c.chars().all(|x| x.is_ascii_digit()) {
+            if is_digit(c) {
                 return self.number(source);
             }
             if c.chars().all(is_alpha) {
@@ -124,7 +134,7 @@ impl Scanner {
 
     fn make_token_if_matches(
         &mut self,
-        source: &String,
+        source: &str,
         string: &str,
         token_true: TokenType,
         token_false: TokenType,
@@ -137,63 +147,40 @@ impl Scanner {
         self.make_token(t)
     }
 
-    fn number(&mut self, source: &String) -> Token {
-        loop {
-            if self
-                .peek(source, 0)
-                .map_or(true, |x| x.chars().all(|y| y.is_ascii_digit()))
-            {
-                break;
-            } else {
-                self.advance(source);
-            }
+    fn number(&mut self, source: &str) -> Token {
+        while self.peek(source, 0).is_some_and(is_digit) {
+            self.advance(source);
         }
-        if self.peek(source, 0).map_or(false, |x| x == ".")
-            && self
-                .peek(source, 1)
-                .map_or(false, |x| x.chars().all(|y| y.is_ascii_digit()))
-        {
+        if self.peek(source, 0) == Some(".") && self.peek(source, 1).is_some_and(is_digit) {
             self.advance(source);
-            loop {
-                if self
-                    .peek(source, 0)
-                    .map_or(true, |x| x.chars().all(|y| y.is_ascii_digit()))
-                {
-                    break;
-                } else {
-                    self.advance(source);
-                }
+            while self.peek(source, 0).is_some_and(is_digit) {
+                self.advance(source);
             }
         }
         self.make_token(TokenType::NUMBER)
     }
 
-    fn identifier(&mut self, source: &String) -> Token {
-        while (self
+    fn identifier(&mut self, source: &str) -> Token {
+        while self
             .peek(source, 0)
-            .map_or(false, |x| x.chars().all(is_alpha)))
-            || (self
-                .peek(source, 0)
-                .map_or(false, |x| x.chars().all(|y| y.is_ascii_digit())))
+            .is_some_and(|x| x.chars().all(is_alpha) || is_digit(x))
         {
             self.advance(source);
         }
-        return self.make_token(self.identifier_type(source));
+        self.make_token(self.identifier_type(source))
     }
 
-    fn identifier_type(&self, source: &String) -> TokenType {
+    fn identifier_type(&self, source: &str) -> TokenType {
         match source.get(self.start..=self.start).unwrap() {
             "a" => return self.check_keyword(source, 1, 2, "nd", TokenType::AND),
             "c" => return self.check_keyword(source, 1, 4, "lass", TokenType::CLASS),
             "e" => return self.check_keyword(source, 1, 3, "lse", TokenType::ELSE),
-            "f" => {
-                if self.current - self.start > 1 {
-                    match source.get(self.start + 1..=self.start + 1).unwrap() {
-                        "a" => return self.check_keyword(source, 2, 3, "lse", TokenType::FALSE),
-                        "o" => return self.check_keyword(source, 2, 1, "r", TokenType::FOR),
-                        "u" => return self.check_keyword(source, 2, 1, "n", TokenType::FUN),
-                        _ => (),
-                    }
+            "f" if self.current - self.start > 1 => {
+                match source.get(self.start + 1..=self.start + 1).unwrap() {
+                    "a" => return self.check_keyword(source, 2, 3, "lse", TokenType::FALSE),
+                    "o" => return self.check_keyword(source, 2, 1, "r", TokenType::FOR),
+                    "u" => return self.check_keyword(source, 2, 1, "n", TokenType::FUN),
+                    _ => (),
                 }
             }
             "i" => return self.check_keyword(source, 1, 1, "f", TokenType::IF),
@@ -202,33 +189,30 @@ impl Scanner {
             "p" => return self.check_keyword(source, 1, 4, "rint", TokenType::PRINT),
             "r" => return self.check_keyword(source, 1, 5, "eturn", TokenType::RETURN),
             "s" => return self.check_keyword(source, 1, 4, "uper", TokenType::SUPER),
-            "t" => {
-                if self.current - self.start > 1 {
-                    match source.get(self.start + 1..=self.start + 1).unwrap() {
-                        "h" => return self.check_keyword(source, 2, 2, "is", TokenType::THIS),
-                        "r" => return self.check_keyword(source, 2, 2, "ue", TokenType::TRUE),
-                        _ => (),
-                    }
+            "t" if self.current - self.start > 1 => {
+                match source.get(self.start + 1..=self.start + 1).unwrap() {
+                    "h" => return self.check_keyword(source, 2, 2, "is", TokenType::THIS),
+                    "r" => return self.check_keyword(source, 2, 2, "ue", TokenType::TRUE),
+                    _ => (),
                 }
             }
             "v" => return self.check_keyword(source, 1, 2, "ar", TokenType::VAR),
             "w" => return self.check_keyword(source, 1, 4, "hile", TokenType::WHILE),
             _ => (),
         }
-        return TokenType::IDENTIFIER;
+        TokenType::IDENTIFIER
     }
 
     fn check_keyword(
         &self,
-        source: &String,
+        source: &str,
         start: usize,
         length: usize,
         s: &str,
         token_type: TokenType,
     ) -> TokenType {
-        if source
-            .get(self.start + start..=(self.start + start + length))
-            .map_or(false, |x| x == s)
+        if self.current - self.start == start + length
+            && source.get(self.start + start..self.start + start + length) == Some(s)
         {
             token_type
         } else {
@@ -236,8 +220,8 @@ impl Scanner {
         }
     }
 
-    fn string(&mut self, source: &String) -> Token {
-        while self.peek(source, 0) == Some("\"") && !self.is_at_end(source) {
+    fn string(&mut self, source: &str) -> Token {
+        while self.peek(source, 0) != Some("\"") && !self.is_at_end(source) {
             if self.peek(source, 0) == Some("\n") {
                 self.line += 1;
             }
@@ -251,16 +235,18 @@ impl Scanner {
         }
     }
 
-    pub fn advance<'a>(&'a mut self, source: &'a String) -> &'a str {
-        self.current += 1;
-        source.get((self.current - 1)..self.current).unwrap()
+    pub fn advance<'a>(&'a mut self, source: &'a str) -> &'a str {
+        let start = self.current;
+        let c = source[start..].chars().next().unwrap();
+        self.current += c.len_utf8();
+        &source[start..self.current]
     }
 
-    fn matches(&mut self, source: &String, expected: &str) -> bool {
+    fn matches(&mut self, source: &str, expected: &str) -> bool {
         if self.is_at_end(source) {
             return false;
         };
-        if source.get(self.current..self.current + 1).unwrap() != expected {
+        if source.get(self.current..self.current + 1) != Some(expected) {
             false
         } else {
             self.current += 1;
@@ -280,13 +266,13 @@ impl Scanner {
     fn error_token(&self, message: String) -> Token {
         Token {
             token_type: TokenType::ERROR(message),
-            start: 0,
-            length: 0,
+            start: self.start,
+            length: self.current - self.start,
             line: self.line,
         }
     }
 
-    fn is_at_end(&self, source: &String) -> bool {
+    fn is_at_end(&self, source: &str) -> bool {
         self.current >= source.len()
     }
 }
@@ -347,3 +333,60 @@ pub struct Token {
     pub length: usize,
     pub line: usize,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scan(source: &str) -> Vec<(TokenType, usize)> {
+        let mut scanner = Scanner::new();
+        let mut tokens = Vec::new();
+        loop {
+            let token = scanner.scan_token(source);
+            if token.token_type == TokenType::EOF {
+                return tokens;
+            }
+            tokens.push((token.token_type, token.length));
+        }
+    }
+
+    #[test]
+    fn numbers_include_every_digit() {
+        assert_eq!(scan("123"), [(TokenType::NUMBER, 3)]);
+        assert_eq!(scan("1.25"), [(TokenType::NUMBER, 4)]);
+        assert_eq!(scan("1."), [(TokenType::NUMBER, 1), (TokenType::DOT, 1)]);
+    }
+
+    #[test]
+    fn keywords_must_match_the_whole_identifier() {
+        assert_eq!(scan("for"), [(TokenType::FOR, 3)]);
+        assert_eq!(scan("fo"), [(TokenType::IDENTIFIER, 2)]);
+        assert_eq!(scan("format"), [(TokenType::IDENTIFIER, 6)]);
+        assert_eq!(scan("this"), [(TokenType::THIS, 4)]);
+        assert_eq!(scan("th"), [(TokenType::IDENTIFIER, 2)]);
+    }
+
+    #[test]
+    fn strings_end_at_the_closing_quote() {
+        assert_eq!(
+            scan("\"ab\" 1"),
+            [(TokenType::STRING, 4), (TokenType::NUMBER, 1)]
+        );
+        assert_eq!(
+            scan("\"ab"),
+            [(TokenType::ERROR("Unterminated string.".into()), 3)]
+        );
+    }
+
+    #[test]
+    fn non_ascii_text_is_scanned_by_character() {
+        assert_eq!(
+            scan("\"héllo\" // café\n!é"),
+            [
+                (TokenType::STRING, 8),
+                (TokenType::BANG, 1),
+                (TokenType::ERROR("unexpected character".into()), 2)
+            ]
+        );
+    }
+}
@@ -0,0 +1,167 @@
+use std::io::{self, Write};
+
+use crate::{
+    chunk::Chunk,
+    debug::{disassemble_chunk, disassemble_instruction},
+    memory::Heap,
+    object::ObjFunction,
+    value::{print_value, Value},
+};
+
+/// Hooks the compiler and VM call as they work. Every method does nothing by
+/// default, so implementations only override the events they care about.
+pub trait Tracer {
+    /// Called before the instruction at `offset` in `chunk` is executed.
+    fn before_instruction(
+        &mut self,
+        _chunk: &Chunk,
+        _offset: usize,
+        _stack: &[Value],
+        _heap: &Heap,
+    ) {
+    }
+
+    /// Called once a new call frame has been pushed for `function`. Calls to
+    /// native functions push no frame, so they do not trigger this.
+    fn on_call(&mut self, _function: &ObjFunction, _heap: &Heap) {}
+
+    /// Called when `function` returns `result` and its frame is discarded.
+    fn on_return(&mut self, _function: &ObjFunction, _result: Value, _heap: &Heap) {}
+
+    /// Called when the compiler has finished the chunk for a function named
+    /// `name`, or `<script>` for top-level code.
+    fn on_compile_chunk(&mut self, _chunk: &Chunk, _name: &str, _heap: &Heap) {}
+}
+
+/// A tracer that ignores every event.
+#[derive(Debug, Default)]
+pub struct NoopTracer;
+
+impl Tracer for NoopTracer {}
+
+/// Prints each compiled chunk, then the stack and the disassembly of every
+/// instruction as it runs, with a line for each call and return.
+pub struct DisassemblyTracer {
+    out: Box<dyn Write>,
+}
+
+impl DisassemblyTracer {
+    pub fn new(out: Box<dyn Write>) -> Self {
+        DisassemblyTracer { out }
+    }
+
+    fn print_stack(&mut self, stack: &[Value], heap: &Heap) -> io::Result<()> {
+        write!(self.out, "          ")?;
+        for value in stack {
+            write!(self.out, "[ ")?;
+            print_value(&mut self.out, value, heap)?;
+            write!(self.out, " ]")?;
+        }
+        writeln!(self.out)
+    }
+}
+
+/// How a function is named in traces: `f()`, or `script` for top-level code.
+fn function_name(function: &ObjFunction, heap: &Heap) -> String {
+    match function.name {
+        Some(name) => format!("{}()", heap.as_string(name).unwrap_or_default()),
+        None => "script".to_string(),
+    }
+}
+
+impl Default for DisassemblyTracer {
+    fn default() -> Self {
+        DisassemblyTracer::new(Box::new(io::stdout()))
+    }
+}
+
+// Tracing is best effort, so write errors are ignored rather than
+// interrupting the program being traced.
+impl Tracer for DisassemblyTracer {
+    fn before_instruction(&mut self, chunk: &Chunk, offset: usize, stack: &[Value], heap: &Heap) {
+        let _ = self.print_stack(stack, heap);
+        let _ = disassemble_instruction(&mut self.out, chunk, offset, heap);
+    }
+
+    fn on_call(&mut self, function: &ObjFunction, heap: &Heap) {
+        let _ = writeln!(self.out, "-> {}", function_name(function, heap));
+    }
+
+    fn on_return(&mut self, function: &ObjFunction, result: Value, heap: &Heap) {
+        let _ = write!(self.out, "<- {} returned ", function_name(function, heap));
+        let _ = print_value(&mut self.out, &result, heap);
+        let _ = writeln!(self.out);
+    }
+
+    fn on_compile_chunk(&mut self, chunk: &Chunk, name: &str, heap: &Heap) {
+        let _ = disassemble_chunk(&mut self.out, chunk, name, heap);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::*;
+    use crate::vm::VM;
+
+    /// Records every event as a line of text.
+    struct RecordingTracer(Rc<RefCell<Vec<String>>>);
+
+    impl Tracer for RecordingTracer {
+        fn before_instruction(&mut self, chunk: &Chunk, offset: usize, _: &[Value], _: &Heap) {
+            let (instruction, _) = chunk.instruction_at(offset);
+            let name = instruction.opcode().map_or("?", |op| op.info().name);
+            self.0.borrow_mut().push(name.to_string());
+        }
+
+        fn on_call(&mut self, function: &ObjFunction, heap: &Heap) {
+            let name = function_name(function, heap);
+            self.0.borrow_mut().push(format!("call {}", name));
+        }
+
+        fn on_return(&mut self, function: &ObjFunction, result: Value, heap: &Heap) {
+            let mut text = Vec::new();
+            print_value(&mut text, &result, heap).unwrap();
+            let name = function_name(function, heap);
+            let result = String::from_utf8(text).unwrap();
+            self.0
+                .borrow_mut()
+                .push(format!("return {} {}", name, result));
+        }
+
+        fn on_compile_chunk(&mut self, _: &Chunk, name: &str, _: &Heap) {
+            self.0.borrow_mut().push(format!("compile {}", name));
+        }
+    }
+
+    #[test]
+    fn events_arrive_in_execution_order() {
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let mut vm = VM::new();
+        vm.set_tracer(Box::new(RecordingTracer(events.clone())));
+        vm.interpret("fun f(x) { return x; }\nf(1);").unwrap();
+
+        assert_eq!(
+            *events.borrow(),
+            [
+                "compile f",
+                "compile <script>",
+                "call script",
+                "OP_CLOSURE",
+                "OP_DEFINE_GLOBAL",
+                "OP_GET_GLOBAL",
+                "OP_CONSTANT",
+                "OP_CALL",
+                "call f()",
+                "OP_GET_LOCAL",
+                "OP_RETURN",
+                "return f() 1",
+                "OP_POP",
+                "OP_NIL",
+                "OP_RETURN",
+                "return script nil",
+            ]
+        );
+    }
+}
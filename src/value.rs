@@ -1,5 +1,35 @@
-pub type Value = f64;
+use std::io::{self, Write};
 
-pub fn print_value(value: &Value) {
-    print!("{}", value);
+use crate::{
+    memory::Heap,
+    object::{print_object, ObjRef},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Value {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    Obj(ObjRef),
+}
+
+impl Value {
+    pub fn is_falsey(&self) -> bool {
+        matches!(self, Value::Nil | Value::Bool(false))
+    }
+}
+
+/// Strings are interned, so comparing object references is enough to compare
+/// their contents.
+pub fn values_equal(a: &Value, b: &Value) -> bool {
+    a == b
+}
+
+pub fn print_value(out: &mut dyn Write, value: &Value, heap: &Heap) -> io::Result<()> {
+    match value {
+        Value::Nil => write!(out, "nil"),
+        Value::Bool(b) => write!(out, "{}", b),
+        Value::Number(n) => write!(out, "{}", n),
+        Value::Obj(o) => print_object(out, *o, heap),
+    }
 }
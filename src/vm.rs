@@ -1,108 +1,905 @@
 #![allow(non_camel_case_types)]
 use std::{
-    ops::{Add, Div, Mul, Sub},
-    ptr::null,
+    collections::HashMap,
+    fmt,
+    io::{self, Write},
+    time::{SystemTime, UNIX_EPOCH},
 };
 
 use crate::{
-    chunk::{Chunk, OpCode},
-    compile::{self, compile},
-    debug::{disassemble_chunk, disassemble_instruction},
-    value::{print_value, Value},
+    chunk::Chunk,
+    compile::{compile, compile_repl},
+    diagnostic::{render, Diagnostic},
+    instruction::Instruction,
+    memory::Heap,
+    object::{
+        NativeFn, ObjBoundMethod, ObjClass, ObjClosure, ObjInstance, ObjNative, ObjRef, ObjUpvalue,
+        Object,
+    },
+    trace::{NoopTracer, Tracer},
+    value::{print_value, values_equal, Value},
 };
 
+#[derive(Debug)]
 pub enum InterpretError {
     INTERPRET_COMPILE_ERROR,
     INTERPRET_RUNTIME_ERROR,
 }
 
-const STACK_MAX: usize = 256;
+const FRAMES_MAX: usize = 64 * 1024;
+const STACK_SIZE_MAX: usize = 8 * 1024 * 1024;
+/// How many of the innermost and of the outermost frames a runtime error's
+/// stack trace shows.
+const TRACE_FRAMES_SHOWN: usize = 10;
+/// Stack slots reserved up front. The stack grows past this on demand.
+const STACK_INITIAL: usize = u8::MAX as usize + 1;
 
-pub struct VM {
-    chunk: Chunk,
+/// Tunable limits for a `VM`.
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// The deepest the call stack may grow before a "Stack overflow." runtime
+    /// error is raised.
+    pub max_frames: usize,
+    /// The most memory, in bytes, the value stack may use before a "Stack
+    /// overflow." runtime error is raised.
+    pub max_stack_size: usize,
+    /// Run the garbage collector before every allocation instead of waiting
+    /// for the heap to grow.
+    pub stress_gc: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            max_frames: FRAMES_MAX,
+            max_stack_size: STACK_SIZE_MAX,
+            stress_gc: false,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct CallFrame {
+    closure: ObjRef,
     ip: usize,
+    /// Index of the frame's first stack slot, which holds the callee.
+    slots: usize,
+}
+
+pub struct VM {
+    config: Config,
+    frames: Vec<CallFrame>,
+    /// Frames and open upvalues refer to slots by index, so the stack can be
+    /// reallocated as it grows.
     stack: Vec<Value>,
+    /// The number of slots `config.max_stack_size` allows.
+    stack_limit: usize,
+    pub(crate) heap: Heap,
+    globals: HashMap<ObjRef, Value>,
+    /// Upvalues that still point into the stack.
+    open_upvalues: Vec<ObjRef>,
+    init_string: ObjRef,
+    pub(crate) tracer: Box<dyn Tracer>,
+    /// Where `print` statements write.
+    pub(crate) out: Box<dyn Write>,
+    /// Where compile and runtime errors are reported.
+    pub(crate) err: Box<dyn Write>,
+    /// The script's command-line arguments.
+    args: Vec<String>,
+}
+
+impl fmt::Debug for VM {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("VM")
+            .field("config", &self.config)
+            .field("frames", &self.frames)
+            .field("stack", &self.stack)
+            .field("globals", &self.globals)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for VM {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl VM {
-    fn read_constant(&mut self) -> Value {
-        let constant = self.chunk.values[self.chunk.codes[self.ip] as usize];
-        self.ip += 1;
-        constant
+    fn frame(&self) -> &CallFrame {
+        self.frames.last().unwrap()
+    }
+
+    fn frame_mut(&mut self) -> &mut CallFrame {
+        self.frames.last_mut().unwrap()
+    }
+
+    fn chunk(&self) -> &Chunk {
+        let function = self.heap.as_closure(self.frame().closure).function;
+        &self.heap.as_function(function).chunk
+    }
+
+    fn constant(&self, index: usize) -> Value {
+        self.chunk().values[index]
+    }
+
+    fn string_constant(&self, index: usize) -> ObjRef {
+        match self.constant(index) {
+            Value::Obj(obj) => obj,
+            _ => unreachable!("constant is not a string"),
+        }
     }
 
     fn run(&mut self) -> Result<(), InterpretError> {
         loop {
-            print!("          ");
-            self.stack.iter().for_each(|v| {
-                print!("[ ");
-                print_value(v);
-                print!(" ]");
-            });
-            println!();
-            disassemble_instruction(&self.chunk, self.ip);
-
-            let instruction = self.chunk.codes[self.ip];
-            self.ip += 1;
-            if let Ok(c) = OpCode::try_from(instruction) {
-                match c {
-                    OpCode::OP_CONSTANT => {
-                        let constant = self.read_constant();
-                        self.push(constant);
+            let ip = self.frame().ip;
+            let function = self.heap.as_closure(self.frame().closure).function;
+            let chunk = &self.heap.as_function(function).chunk;
+            self.tracer
+                .before_instruction(chunk, ip, &self.stack, &self.heap);
+
+            let (instruction, next) = chunk.instruction_at(ip);
+            self.frame_mut().ip = next;
+            match instruction {
+                Instruction::Constant(index) | Instruction::ConstantLong(index) => {
+                    let constant = self.constant(index);
+                    self.push(constant)?;
+                }
+                Instruction::Nil => self.push(Value::Nil)?,
+                Instruction::True => self.push(Value::Bool(true))?,
+                Instruction::False => self.push(Value::Bool(false))?,
+                Instruction::Equal => {
+                    let b = self.pop()?;
+                    let a = self.pop()?;
+                    self.push(Value::Bool(values_equal(&a, &b)))?;
+                }
+                Instruction::Greater => self.binop(|a, b| Value::Bool(a > b))?,
+                Instruction::Less => self.binop(|a, b| Value::Bool(a < b))?,
+                Instruction::Not => {
+                    let value = self.pop()?.is_falsey();
+                    self.push(Value::Bool(value))?;
+                }
+                Instruction::Negate => match self.peek(0) {
+                    Value::Number(n) => {
+                        self.pop()?;
+                        self.push(Value::Number(-n))?;
+                    }
+                    _ => return Err(self.runtime_error("Operand must be a number.")),
+                },
+                Instruction::Print => {
+                    let value = self.pop()?;
+                    let written = print_value(&mut self.out, &value, &self.heap)
+                        .and_then(|()| writeln!(self.out));
+                    if let Err(error) = written {
+                        let message = format!("Could not write output: {}.", error);
+                        return Err(self.runtime_error(&message));
+                    }
+                }
+                Instruction::Pop => {
+                    self.pop()?;
+                }
+                Instruction::DefineGlobal(index) | Instruction::DefineGlobalLong(index) => {
+                    let name = self.string_constant(index);
+                    let value = self.peek(0);
+                    self.globals.insert(name, value);
+                    self.pop()?;
+                }
+                Instruction::GetGlobal(index) | Instruction::GetGlobalLong(index) => {
+                    let name = self.string_constant(index);
+                    match self.globals.get(&name) {
+                        Some(&value) => self.push(value)?,
+                        None => return Err(self.undefined_variable(name)),
+                    }
+                }
+                Instruction::SetGlobal(index) | Instruction::SetGlobalLong(index) => {
+                    let name = self.string_constant(index);
+                    let value = self.peek(0);
+                    match self.globals.get_mut(&name) {
+                        Some(global) => *global = value,
+                        None => return Err(self.undefined_variable(name)),
+                    }
+                }
+                Instruction::GetLocal(slot) => {
+                    self.push(self.stack[self.frame().slots + slot as usize])?;
+                }
+                Instruction::SetLocal(slot) => {
+                    let slots = self.frame().slots;
+                    self.stack[slots + slot as usize] = self.peek(0);
+                }
+                Instruction::Jump(offset) => {
+                    self.frame_mut().ip += offset as usize;
+                }
+                Instruction::JumpIfFalse(offset) => {
+                    if self.peek(0).is_falsey() {
+                        self.frame_mut().ip += offset as usize;
+                    }
+                }
+                Instruction::Loop(offset) => {
+                    self.frame_mut().ip -= offset as usize;
+                }
+                Instruction::Call(arg_count) => {
+                    let arg_count = arg_count as usize;
+                    self.call_value(self.peek(arg_count), arg_count)?;
+                }
+                Instruction::Closure {
+                    function,
+                    upvalues: captured,
+                }
+                | Instruction::ClosureLong {
+                    function,
+                    upvalues: captured,
+                } => {
+                    let function = match self.constant(function) {
+                        Value::Obj(function) => function,
+                        _ => unreachable!("constant is not a function"),
+                    };
+                    let mut upvalues = Vec::with_capacity(captured.count);
+                    for upvalue in 0..captured.count {
+                        let upvalue = captured.get(self.chunk(), upvalue);
+                        let index = upvalue.index as usize;
+                        if upvalue.is_local {
+                            let slot = self.frame().slots + index;
+                            upvalues.push(self.capture_upvalue(slot));
+                        } else {
+                            let enclosing = self.heap.as_closure(self.frame().closure);
+                            upvalues.push(enclosing.upvalues[index]);
+                        }
                     }
-                    OpCode::OP_NEGATE => {
-                        let value = -self.pop();
-                        self.push(value);
+                    let closure = self.alloc(Object::Closure(ObjClosure { function, upvalues }));
+                    self.push(Value::Obj(closure))?;
+                }
+                Instruction::GetUpvalue(slot) => {
+                    let closure = self.heap.as_closure(self.frame().closure);
+                    let upvalue = closure.upvalues[slot as usize];
+                    let value = match self.heap.as_upvalue(upvalue) {
+                        ObjUpvalue::Open(location) => self.stack[*location],
+                        ObjUpvalue::Closed(value) => *value,
+                    };
+                    self.push(value)?;
+                }
+                Instruction::SetUpvalue(slot) => {
+                    let closure = self.heap.as_closure(self.frame().closure);
+                    let upvalue = closure.upvalues[slot as usize];
+                    let value = self.peek(0);
+                    match self.heap.as_upvalue_mut(upvalue) {
+                        ObjUpvalue::Open(location) => self.stack[*location] = value,
+                        ObjUpvalue::Closed(closed) => *closed = value,
                     }
-                    OpCode::OP_RETURN => {
-                        print_value(&self.pop());
-                        println!();
+                }
+                Instruction::CloseUpvalue => {
+                    self.close_upvalues(self.stack.len() - 1);
+                    self.pop()?;
+                }
+                Instruction::Class(index) | Instruction::ClassLong(index) => {
+                    let name = self.string_constant(index);
+                    let class = self.alloc(Object::Class(ObjClass {
+                        name,
+                        methods: HashMap::new(),
+                    }));
+                    self.push(Value::Obj(class))?;
+                }
+                Instruction::GetProperty(index) | Instruction::GetPropertyLong(index) => {
+                    let instance = match self.peek(0) {
+                        Value::Obj(obj) if self.heap.as_instance(obj).is_some() => obj,
+                        _ => return Err(self.runtime_error("Only instances have properties.")),
+                    };
+                    let name = self.string_constant(index);
+                    let instance = self.heap.as_instance(instance).unwrap();
+                    match instance.fields.get(&name) {
+                        Some(&value) => {
+                            self.pop()?;
+                            self.push(value)?;
+                        }
+                        None => self.bind_method(instance.class, name)?,
+                    }
+                }
+                Instruction::SetProperty(index) | Instruction::SetPropertyLong(index) => {
+                    let instance = match self.peek(1) {
+                        Value::Obj(obj) if self.heap.as_instance(obj).is_some() => obj,
+                        _ => return Err(self.runtime_error("Only instances have fields.")),
+                    };
+                    let name = self.string_constant(index);
+                    let value = self.peek(0);
+                    let fields = &mut self.heap.as_instance_mut(instance).unwrap().fields;
+                    fields.insert(name, value);
+                    self.heap.resize(instance);
+                    self.pop()?;
+                    self.pop()?;
+                    self.push(value)?;
+                }
+                Instruction::Method(index) | Instruction::MethodLong(index) => {
+                    let name = self.string_constant(index);
+                    self.define_method(name)?;
+                }
+                Instruction::Invoke { name, arg_count }
+                | Instruction::InvokeLong { name, arg_count } => {
+                    let method = self.string_constant(name);
+                    self.invoke(method, arg_count as usize)?;
+                }
+                Instruction::Inherit => {
+                    let superclass = match self.peek(1) {
+                        Value::Obj(obj) if matches!(self.heap.get(obj), Object::Class(_)) => obj,
+                        _ => return Err(self.runtime_error("Superclass must be a class.")),
+                    };
+                    let subclass = match self.peek(0) {
+                        Value::Obj(obj) => obj,
+                        _ => unreachable!("OP_INHERIT is only emitted for classes"),
+                    };
+                    // Copy the inherited methods down so that method
+                    // lookup never has to walk the class hierarchy.
+                    let methods = self.heap.as_class(superclass).methods.clone();
+                    self.heap.as_class_mut(subclass).methods.extend(methods);
+                    self.heap.resize(subclass);
+                    self.pop()?;
+                }
+                Instruction::GetSuper(index) | Instruction::GetSuperLong(index) => {
+                    let name = self.string_constant(index);
+                    let superclass = self.pop_class()?;
+                    self.bind_method(superclass, name)?;
+                }
+                Instruction::SuperInvoke { name, arg_count }
+                | Instruction::SuperInvokeLong { name, arg_count } => {
+                    let method = self.string_constant(name);
+                    let superclass = self.pop_class()?;
+                    self.invoke_from_class(superclass, method, arg_count as usize)?;
+                }
+                Instruction::Return => {
+                    let result = self.pop()?;
+                    let frame = self.frames.pop().unwrap();
+                    let function = self.heap.as_closure(frame.closure).function;
+                    self.tracer
+                        .on_return(self.heap.as_function(function), result, &self.heap);
+                    self.close_upvalues(frame.slots);
+                    if self.frames.is_empty() {
+                        self.pop()?;
                         return Ok(());
                     }
-                    OpCode::OP_ADD => self.binop(Add::add),
-                    OpCode::OP_SUBTRACT => self.binop(Sub::sub),
-                    OpCode::OP_MULTIPLY => self.binop(Mul::mul),
-                    OpCode::OP_DIVIDE => self.binop(Div::div),
+                    self.stack.truncate(frame.slots);
+                    self.push(result)?;
+                }
+                Instruction::Add => match (self.peek(1), self.peek(0)) {
+                    (Value::Obj(a), Value::Obj(b))
+                        if self.heap.as_string(a).is_some() && self.heap.as_string(b).is_some() =>
+                    {
+                        self.concatenate(a, b)?
+                    }
+                    (Value::Number(a), Value::Number(b)) => {
+                        self.pop()?;
+                        self.pop()?;
+                        self.push(Value::Number(a + b))?;
+                    }
+                    _ => {
+                        return Err(
+                            self.runtime_error("Operands must be two numbers or two strings.")
+                        )
+                    }
+                },
+                Instruction::Subtract => self.binop(|a, b| Value::Number(a - b))?,
+                Instruction::Multiply => self.binop(|a, b| Value::Number(a * b))?,
+                Instruction::Divide => self.binop(|a, b| Value::Number(a / b))?,
+                Instruction::Unknown(byte) => {
+                    let message = format!("Unknown opcode {}.", byte);
+                    return Err(self.runtime_error(&message));
                 }
-            } else {
-                panic!()
             }
         }
     }
 
-    fn binop(&mut self, op: fn(a: Value, b: Value) -> Value) {
-        let vb = self.pop();
-        let va = self.pop();
-        self.push(op(va, vb));
+    fn call_value(&mut self, callee: Value, arg_count: usize) -> Result<(), InterpretError> {
+        if let Value::Obj(obj) = callee {
+            match self.heap.get(obj) {
+                Object::Closure(_) => return self.call(obj, arg_count),
+                Object::BoundMethod(bound) => {
+                    let method = bound.method;
+                    let slot = self.stack.len() - arg_count - 1;
+                    self.stack[slot] = bound.receiver;
+                    return self.call(method, arg_count);
+                }
+                Object::Class(_) => {
+                    let instance = self.alloc(Object::Instance(ObjInstance {
+                        class: obj,
+                        fields: HashMap::new(),
+                    }));
+                    let slot = self.stack.len() - arg_count - 1;
+                    self.stack[slot] = Value::Obj(instance);
+                    let initializer = self.heap.as_class(obj).methods.get(&self.init_string);
+                    return match initializer {
+                        Some(&Value::Obj(initializer)) => self.call(initializer, arg_count),
+                        _ if arg_count != 0 => {
+                            let message = format!("Expected 0 arguments but got {}.", arg_count);
+                            Err(self.runtime_error(&message))
+                        }
+                        _ => Ok(()),
+                    };
+                }
+                Object::Native(native) => {
+                    let (arity, function) = (native.arity, native.function);
+                    return self.call_native(arity, function, arg_count);
+                }
+                _ => {}
+            }
+        }
+        Err(self.runtime_error("Can only call functions and classes."))
+    }
+
+    fn invoke(&mut self, name: ObjRef, arg_count: usize) -> Result<(), InterpretError> {
+        let receiver = match self.peek(arg_count) {
+            Value::Obj(obj) => self.heap.as_instance(obj),
+            _ => None,
+        };
+        let Some(instance) = receiver else {
+            return Err(self.runtime_error("Only instances have methods."));
+        };
+        // A field shadows a method of the same name, and may hold any callable.
+        if let Some(&value) = instance.fields.get(&name) {
+            let slot = self.stack.len() - arg_count - 1;
+            self.stack[slot] = value;
+            return self.call_value(value, arg_count);
+        }
+        self.invoke_from_class(instance.class, name, arg_count)
+    }
+
+    fn invoke_from_class(
+        &mut self,
+        class: ObjRef,
+        name: ObjRef,
+        arg_count: usize,
+    ) -> Result<(), InterpretError> {
+        match self.heap.as_class(class).methods.get(&name) {
+            Some(&Value::Obj(method)) => self.call(method, arg_count),
+            _ => Err(self.undefined_property(name)),
+        }
+    }
+
+    /// Replaces the instance on top of the stack with its method `name`,
+    /// bound to that instance.
+    fn bind_method(&mut self, class: ObjRef, name: ObjRef) -> Result<(), InterpretError> {
+        let method = match self.heap.as_class(class).methods.get(&name) {
+            Some(&Value::Obj(method)) => method,
+            _ => return Err(self.undefined_property(name)),
+        };
+        let bound = self.alloc(Object::BoundMethod(ObjBoundMethod {
+            receiver: self.peek(0),
+            method,
+        }));
+        self.pop()?;
+        self.push(Value::Obj(bound))?;
+        Ok(())
+    }
+
+    /// Pops the superclass pushed for a `super` expression.
+    fn pop_class(&mut self) -> Result<ObjRef, InterpretError> {
+        match self.pop()? {
+            Value::Obj(class) => Ok(class),
+            _ => unreachable!("'super' always refers to a class"),
+        }
+    }
+
+    fn define_method(&mut self, name: ObjRef) -> Result<(), InterpretError> {
+        let method = self.peek(0);
+        let class = match self.peek(1) {
+            Value::Obj(class) => class,
+            _ => unreachable!("methods are only defined on classes"),
+        };
+        self.heap.as_class_mut(class).methods.insert(name, method);
+        self.heap.resize(class);
+        self.pop()?;
+        Ok(())
+    }
+
+    fn call_native(
+        &mut self,
+        arity: usize,
+        function: NativeFn,
+        arg_count: usize,
+    ) -> Result<(), InterpretError> {
+        if arg_count != arity {
+            let message = format!("Expected {} arguments but got {}.", arity, arg_count);
+            return Err(self.runtime_error(&message));
+        }
+        let args_start = self.stack.len() - arg_count;
+        let args = self.stack[args_start..].to_vec();
+        match function(self, &args) {
+            Ok(result) => {
+                self.stack.truncate(args_start - 1);
+                self.push(result)?;
+                Ok(())
+            }
+            Err(message) => Err(self.runtime_error(&message)),
+        }
     }
 
-    pub fn new(chunk: Chunk) -> VM {
-        VM {
+    fn call(&mut self, closure: ObjRef, arg_count: usize) -> Result<(), InterpretError> {
+        let function = self.heap.as_closure(closure).function;
+        let arity = self.heap.as_function(function).arity;
+        if arg_count != arity {
+            let message = format!("Expected {} arguments but got {}.", arity, arg_count);
+            return Err(self.runtime_error(&message));
+        }
+        if self.frames.len() == self.config.max_frames {
+            return Err(self.runtime_error("Stack overflow."));
+        }
+        self.frames.push(CallFrame {
+            closure,
             ip: 0,
-            chunk,
-            stack: Vec::with_capacity(STACK_MAX),
+            slots: self.stack.len() - arg_count - 1,
+        });
+        self.tracer
+            .on_call(self.heap.as_function(function), &self.heap);
+        Ok(())
+    }
+
+    /// Returns the open upvalue for a stack slot, creating it if no closure
+    /// has captured the slot yet.
+    fn capture_upvalue(&mut self, slot: usize) -> ObjRef {
+        let existing = self.open_upvalues.iter().find(|&&upvalue| {
+            matches!(self.heap.as_upvalue(upvalue), ObjUpvalue::Open(location) if *location == slot)
+        });
+        if let Some(&upvalue) = existing {
+            return upvalue;
         }
+        let upvalue = self.alloc(Object::Upvalue(ObjUpvalue::Open(slot)));
+        self.open_upvalues.push(upvalue);
+        upvalue
     }
 
-    fn push(&mut self, value: Value) {
-        if self.stack.len() == STACK_MAX {
-            panic!("STACK OVERFLOW")
-        } else {
-            self.stack.push(value);
+    /// Moves every captured variable at or above `last` off the stack.
+    fn close_upvalues(&mut self, last: usize) {
+        let (stack, heap) = (&self.stack, &mut self.heap);
+        self.open_upvalues.retain(|&upvalue| {
+            let upvalue = heap.as_upvalue_mut(upvalue);
+            match *upvalue {
+                ObjUpvalue::Open(location) if location >= last => {
+                    *upvalue = ObjUpvalue::Closed(stack[location]);
+                    false
+                }
+                _ => true,
+            }
+        });
+    }
+
+    fn binop(&mut self, op: fn(a: f64, b: f64) -> Value) -> Result<(), InterpretError> {
+        match (self.peek(1), self.peek(0)) {
+            (Value::Number(a), Value::Number(b)) => {
+                self.pop()?;
+                self.pop()?;
+                self.push(op(a, b))?;
+                Ok(())
+            }
+            _ => Err(self.runtime_error("Operands must be numbers.")),
         }
     }
 
-    fn pop(&mut self) -> Value {
-        self.stack.pop().unwrap()
+    fn concatenate(&mut self, a: ObjRef, b: ObjRef) -> Result<(), InterpretError> {
+        let mut chars = self.heap.as_string(a).unwrap().to_string();
+        chars.push_str(self.heap.as_string(b).unwrap());
+        self.maybe_collect_garbage();
+        let result = self.heap.take_string(chars);
+        self.pop()?;
+        self.pop()?;
+        self.push(Value::Obj(result))
+    }
+
+    fn undefined_property(&mut self, name: ObjRef) -> InterpretError {
+        let message = format!(
+            "Undefined property '{}'.",
+            self.heap.as_string(name).unwrap()
+        );
+        self.runtime_error(&message)
     }
 
-    pub fn interpret(&mut self, source: &String) -> Result<(), InterpretError> {
-        let mut chunk = Chunk::default();
-        if compile(&source, &mut chunk) {
-            let mut vm = VM::new(chunk);
-            return vm.run();
-        } else {
-            return Err(InterpretError::INTERPRET_COMPILE_ERROR);
+    fn undefined_variable(&mut self, name: ObjRef) -> InterpretError {
+        let message = format!(
+            "Undefined variable '{}'.",
+            self.heap.as_string(name).unwrap()
+        );
+        self.runtime_error(&message)
+    }
+
+    /// Reports a runtime error with a trace of the active calls, innermost
+    /// first, and resets the VM so it can run more code.
+    fn runtime_error(&mut self, message: &str) -> InterpretError {
+        // There is nowhere left to report a failure to write an error, so
+        // those are ignored.
+        let _ = writeln!(self.err, "{}", message);
+        // A stack overflow can leave thousands of frames, so only the ends of
+        // a deep trace are shown.
+        let hidden = self.frames.len().saturating_sub(2 * TRACE_FRAMES_SHOWN);
+        for (depth, frame) in self.frames.iter().rev().enumerate() {
+            if hidden > 0 && depth == TRACE_FRAMES_SHOWN {
+                let _ = writeln!(self.err, "... {} more frames", hidden);
+            }
+            if (TRACE_FRAMES_SHOWN..TRACE_FRAMES_SHOWN + hidden).contains(&depth) {
+                continue;
+            }
+            let function = self
+                .heap
+                .as_function(self.heap.as_closure(frame.closure).function);
+            // The ip has already moved past the failing instruction.
+            let line = function.chunk.line_at(frame.ip.saturating_sub(1));
+            match function.name {
+                Some(name) => {
+                    let _ = writeln!(
+                        self.err,
+                        "[line {}] in {}()",
+                        line,
+                        self.heap.as_string(name).unwrap()
+                    );
+                }
+                None => {
+                    let _ = writeln!(self.err, "[line {}] in script", line);
+                }
+            }
         }
+        // Closures that outlive this run keep the values they captured.
+        self.close_upvalues(0);
+        self.stack.clear();
+        self.frames.clear();
+        InterpretError::INTERPRET_RUNTIME_ERROR
+    }
+
+    pub fn new() -> VM {
+        VM::with_config(Config::default())
+    }
+
+    pub fn with_config(config: Config) -> VM {
+        let mut heap = Heap::default();
+        heap.stress = config.stress_gc;
+        let init_string = heap.copy_string("init");
+        let stack_limit = config.max_stack_size / size_of::<Value>();
+        let mut vm = VM {
+            config,
+            frames: Vec::new(),
+            stack: Vec::with_capacity(STACK_INITIAL),
+            stack_limit,
+            heap,
+            globals: HashMap::new(),
+            open_upvalues: Vec::new(),
+            init_string,
+            tracer: Box::new(NoopTracer),
+            out: Box::new(io::stdout()),
+            err: Box::new(io::stderr()),
+            args: Vec::new(),
+        };
+        vm.define_builtins();
+        vm
+    }
+
+    fn define_builtins(&mut self) {
+        self.define_native("clock", 0, clock_native);
+        self.define_native("argCount", 0, arg_count_native);
+        self.define_native("arg", 1, arg_native);
+    }
+
+    /// Forgets every global the scripts have defined, restoring the built-in
+    /// natives. Objects that are no longer reachable are freed by the next
+    /// collection.
+    pub fn reset(&mut self) {
+        // Closures that outlive this run keep the values they captured.
+        self.close_upvalues(0);
+        self.stack.clear();
+        self.frames.clear();
+        self.globals.clear();
+        self.define_builtins();
+    }
+
+    /// Returns the value of the global variable `name`, if it is defined.
+    pub fn global(&self, name: &str) -> Option<Value> {
+        let name = self.heap.find_string(name)?;
+        self.globals.get(&name).copied()
+    }
+
+    /// Returns the names and values of every global variable, sorted by name.
+    pub fn globals(&self) -> Vec<(&str, Value)> {
+        let mut globals: Vec<_> = self
+            .globals
+            .iter()
+            .map(|(&name, &value)| (self.heap.as_string(name).unwrap(), value))
+            .collect();
+        globals.sort_by(|a, b| a.0.cmp(b.0));
+        globals
+    }
+
+    /// Installs a tracer to observe compilation and execution.
+    pub fn set_tracer(&mut self, tracer: Box<dyn Tracer>) {
+        self.tracer = tracer;
+    }
+
+    /// Redirects the output of `print` statements, which goes to stdout by
+    /// default.
+    pub fn set_output(&mut self, out: Box<dyn Write>) {
+        self.out = out;
+    }
+
+    /// Redirects compile and runtime errors, which go to stderr by default.
+    pub fn set_error_output(&mut self, err: Box<dyn Write>) {
+        self.err = err;
+    }
+
+    /// Sets the command-line arguments scripts can read with `argCount()`
+    /// and `arg(index)`.
+    pub fn set_args(&mut self, args: Vec<String>) {
+        self.args = args;
+    }
+
+    /// Installs a host function as a global that scripts can call.
+    pub fn define_native(&mut self, name: &str, arity: usize, function: NativeFn) {
+        self.maybe_collect_garbage();
+        let name = self.heap.copy_string(name);
+        // Keep the name reachable while the native is allocated.
+        self.stack.push(Value::Obj(name));
+        let native = self.alloc(Object::Native(ObjNative {
+            name,
+            arity,
+            function,
+        }));
+        self.globals.insert(name, Value::Obj(native));
+        self.stack.pop();
+    }
+
+    /// Allocates an object, first collecting garbage if the heap has grown
+    /// enough. Anything the caller still needs must be reachable from the
+    /// VM's roots.
+    fn alloc(&mut self, object: Object) -> ObjRef {
+        self.maybe_collect_garbage();
+        self.heap.alloc(object)
+    }
+
+    fn maybe_collect_garbage(&mut self) {
+        if self.heap.should_collect() {
+            self.mark_roots();
+            self.heap.collect_garbage();
+        }
+    }
+
+    pub(crate) fn mark_roots(&mut self) {
+        for &value in &self.stack {
+            self.heap.mark_value(value);
+        }
+        for frame in &self.frames {
+            self.heap.mark_object(frame.closure);
+        }
+        for &upvalue in &self.open_upvalues {
+            self.heap.mark_object(upvalue);
+        }
+        for (&name, &value) in &self.globals {
+            self.heap.mark_object(name);
+            self.heap.mark_value(value);
+        }
+        self.heap.mark_object(self.init_string);
+    }
+
+    fn push(&mut self, value: Value) -> Result<(), InterpretError> {
+        if self.stack.len() >= self.stack_limit {
+            return Err(self.runtime_error("Stack overflow."));
+        }
+        self.stack.push(value);
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Result<Value, InterpretError> {
+        match self.stack.pop() {
+            Some(value) => Ok(value),
+            None => Err(self.runtime_error("Stack underflow.")),
+        }
+    }
+
+    fn peek(&self, distance: usize) -> Value {
+        self.stack[self.stack.len() - 1 - distance]
+    }
+
+    pub fn interpret(&mut self, source: &str) -> Result<(), InterpretError> {
+        self.run_compiled(source, compile)
+    }
+
+    /// Interprets a line of REPL input, printing the value of a trailing
+    /// expression that has no semicolon.
+    pub fn interpret_repl(&mut self, source: &str) -> Result<(), InterpretError> {
+        self.run_compiled(source, compile_repl)
+    }
+
+    fn run_compiled(
+        &mut self,
+        source: &str,
+        compile: fn(&str, &mut VM) -> Result<ObjRef, Vec<Diagnostic>>,
+    ) -> Result<(), InterpretError> {
+        let function = match compile(source, self) {
+            Ok(function) => function,
+            Err(diagnostics) => {
+                for diagnostic in &diagnostics {
+                    let _ = writeln!(self.err, "{}", render(diagnostic, source));
+                }
+                return Err(InterpretError::INTERPRET_COMPILE_ERROR);
+            }
+        };
+        self.push(Value::Obj(function))?;
+        let closure = self.alloc(Object::Closure(ObjClosure {
+            function,
+            upvalues: Vec::new(),
+        }));
+        self.pop()?;
+        self.push(Value::Obj(closure))?;
+        self.call(closure, 0)?;
+        self.run()
+    }
+}
+
+fn clock_native(_vm: &mut VM, _args: &[Value]) -> Result<Value, String> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|e| e.to_string())?;
+    Ok(Value::Number(now.as_secs_f64()))
+}
+
+fn arg_count_native(vm: &mut VM, _args: &[Value]) -> Result<Value, String> {
+    Ok(Value::Number(vm.args.len() as f64))
+}
+
+/// Returns the argument at `index` as a string, or nil if there is none.
+fn arg_native(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let index = match args[0] {
+        Value::Number(n) if n >= 0.0 && n.fract() == 0.0 => n as usize,
+        _ => return Err("Argument index must be a non-negative integer.".to_string()),
+    };
+    let Some(arg) = vm.args.get(index).cloned() else {
+        return Ok(Value::Nil);
+    };
+    vm.maybe_collect_garbage();
+    Ok(Value::Obj(vm.heap.take_string(arg)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::SharedBuffer;
+
+    const RECURSE: &str = "fun count(n) {\n  if (n == 0) return 0;\n  return 1 + count(n - 1);\n}\nprint count(1000);\n";
+
+    #[test]
+    fn small_stack_limit_overflows_before_frame_limit() {
+        let err = SharedBuffer::default();
+        let mut vm = VM::with_config(Config {
+            max_stack_size: 256 * size_of::<Value>(),
+            ..Config::default()
+        });
+        vm.set_output(Box::new(io::sink()));
+        vm.set_error_output(Box::new(err.clone()));
+        assert!(vm.interpret(RECURSE).is_err());
+        assert!(err.contents().starts_with("Stack overflow.\n"));
+
+        // The same script fits within the default limits.
+        let mut vm = VM::new();
+        vm.set_output(Box::new(io::sink()));
+        assert!(vm.interpret(RECURSE).is_ok());
+    }
+
+    #[test]
+    fn runtime_errors_close_captured_variables() {
+        let out = SharedBuffer::default();
+        let mut vm = VM::new();
+        vm.set_output(Box::new(out.clone()));
+        vm.set_error_output(Box::new(io::sink()));
+        let source = "var g;\nfun f() {\n  var x = \"captured\";\n  fun h() { return x; }\n  g = h;\n  nil();\n}\nf();\n";
+        assert!(vm.interpret(source).is_err());
+
+        vm.interpret("{ var a = \"one\"; var b = \"two\"; print g(); }")
+            .unwrap();
+        assert_eq!(out.contents(), "captured\n");
+    }
+
+    #[test]
+    fn deep_stack_traces_are_elided() {
+        let err = SharedBuffer::default();
+        let mut vm = VM::new();
+        vm.set_error_output(Box::new(err.clone()));
+        assert!(vm.interpret("fun f() { f(); }\nf();").is_err());
+
+        let trace = err.contents();
+        let lines: Vec<&str> = trace.lines().collect();
+        assert_eq!(lines.len(), 2 + 2 * TRACE_FRAMES_SHOWN);
+        assert_eq!(lines[0], "Stack overflow.");
+        assert_eq!(lines[1], "[line 1] in f()");
+        assert!(lines[1 + TRACE_FRAMES_SHOWN].starts_with("... "));
+        assert_eq!(lines.last(), Some(&"[line 2] in script"));
     }
 }
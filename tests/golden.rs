@@ -0,0 +1,31 @@
+use std::{io, path::Path};
+
+use rlox::{
+    harness::{report, run_dir},
+    vm::Config,
+};
+
+fn check_scripts(config: &Config) {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/lox");
+    let results = run_dir(&dir, config).unwrap();
+    assert!(!results.is_empty());
+    if results.iter().any(|result| !result.passed()) {
+        report(&mut io::stderr(), &results).unwrap();
+        panic!("some Lox scripts did not behave as expected");
+    }
+}
+
+#[test]
+fn lox_scripts_match_their_expectations() {
+    check_scripts(&Config::default());
+}
+
+/// Collecting before every allocation frees anything the VM or compiler
+/// forgot to root, which shows up as a wrong result or a panic.
+#[test]
+fn lox_scripts_survive_collecting_on_every_allocation() {
+    check_scripts(&Config {
+        stress_gc: true,
+        ..Config::default()
+    });
+}